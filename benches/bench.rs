@@ -14,7 +14,23 @@ pub fn criterion_benchmark(c: &mut Criterion) {
             let mut ctx = Ctx::new();
             ctx.insert_code(code);
             let parent = &mut ctx.parse_next_expr().unwrap();
-            black_box(eval(parent, &mut ctx));
+            black_box(eval(parent, &mut ctx).unwrap());
+        })
+    });
+
+    c.bench_function("counter_vm", |b| {
+        b.iter(|| {
+            let code = r#"{
+                let x = 0
+                while x<1000 
+                    x = x+1
+                x
+                }"#;
+
+            let mut ctx = Ctx::new();
+            ctx.insert_code(code);
+            let parent = &mut ctx.parse_next_expr().unwrap();
+            black_box(vm::eval(parent, &mut ctx).unwrap());
         })
     });
 
@@ -44,7 +60,27 @@ pub fn criterion_benchmark(c: &mut Criterion) {
             let mut ctx = Ctx::new();
             ctx.insert_code(code);
             let parent = &mut ctx.parse_next_expr().unwrap();
-            black_box(eval(parent, &mut ctx));
+            black_box(eval(parent, &mut ctx).unwrap());
+        })
+    });
+
+    c.bench_function("counter_deep_vm", |b| {
+        b.iter(|| {
+            let code = r#"{
+                let x = 0
+                while x<1000 {
+                    {
+                        {
+                            x = x+1
+                        }
+                    }
+                }
+                x
+                }"#;
+            let mut ctx = Ctx::new();
+            ctx.insert_code(code);
+            let parent = &mut ctx.parse_next_expr().unwrap();
+            black_box(vm::eval(parent, &mut ctx).unwrap());
         })
     });
 
@@ -74,7 +110,18 @@ pub fn criterion_benchmark(c: &mut Criterion) {
             let mut ctx = Ctx::new();
             ctx.insert_code(code);
             let parent = &mut ctx.parse_next_expr().unwrap();
-            black_box(eval(parent, &mut ctx));
+            black_box(eval(parent, &mut ctx).unwrap());
+        })
+    });
+
+    c.bench_function("fib_20_vm", |b| {
+        b.iter(|| {
+            let code = r#"{let fib = (e)=> if e<2 e else fib(e-1)+fib(e-2)
+                fib(20)}"#;
+            let mut ctx = Ctx::new();
+            ctx.insert_code(code);
+            let parent = &mut ctx.parse_next_expr().unwrap();
+            black_box(vm::eval(parent, &mut ctx).unwrap());
         })
     });
 
@@ -92,7 +139,7 @@ pub fn criterion_benchmark(c: &mut Criterion) {
     });
 
     let mut v = Vec::new();
-    for i in 0..1000 {
+    for _ in 0..1000 {
         v.push(N::Num(1.0));
     }
     let arr = N::Array(v.clone());
@@ -110,13 +157,26 @@ pub fn criterion_benchmark(c: &mut Criterion) {
         })
     });
 
+    c.bench_function("map_reduce_vm", |b| {
+        b.iter(|| {
+            let code = r#"{
+                let arr2=  arr((e)=>e*2)
+                arr2 | (a,b)=>a+b
+            }"#;
+            let mut ctx = Ctx::new();
+            ctx.insert_code(code);
+            ctx.set_val("arr", arr.clone());
+            let parent = &mut ctx.parse_next_expr().unwrap();
+            let _ = black_box(vm::eval(parent, &mut ctx));
+        })
+    });
+
     c.bench_function("map_reduce_native", |b| {
         b.iter(|| {
-            let res = black_box(v.clone())
+            black_box(v.clone())
                 .iter()
-                .cloned()
                 .map(|e| e.as_f64() * 2.)
-                .fold(0., |acc, x| acc + x);
+                .fold(0., |acc, x| acc + x)
         })
     });
 }
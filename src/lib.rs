@@ -1,8 +1,15 @@
 #![no_std]
 use log::info;
 extern crate alloc;
-use alloc::{boxed::Box, format, string::String, vec::Vec};
-use core::{ops::Rem, result::Result};
+use alloc::{boxed::Box, collections::BTreeMap, format, rc::Rc, string::String, vec::Vec};
+use core::{
+    cell::{Cell, RefCell},
+    cmp::Ordering,
+    ops::{ControlFlow, Rem},
+    result::Result,
+};
+
+pub mod vm;
 
 pub type ID = String;
 pub type BN = Box<N>;
@@ -15,11 +22,12 @@ macro_rules! bx {
 }
 
 /// fomoscript AST node
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum N {
     FuncCall {
         func: BN,
         args: VN,
+        span: Span,
     },
     Block(VN),
     If {
@@ -31,25 +39,119 @@ pub enum N {
         condition: BN,
         body: BN,
     },
+    /// `for var in iter body`, or `for (var, index) in iter body`
+    For {
+        /// The element, and optionally its index
+        vars: Vec<ID>,
+        iter: BN,
+        body: BN,
+    },
+    /// `match value { pattern if guard => body, ... }`, the first matching arm is evaluated
+    Match {
+        value: BN,
+        arms: Vec<Arm>,
+    },
     Set(ID, BN),
-    Get(ID),
-    Unary(Op, BN),
-    Binary(Op, BN, BN),
+    /// `let [a, ..rest] = value` or `let {x, y} = value`, declares the variables of the pattern
+    Destructure(Box<Pattern>, BN, Span),
+    Get(ID, Span),
+    /// Variable resolved at parse time, `slot` is its index in the function frame.
+    ///
+    /// `depth` counts the functions between the use and the declaration,
+    /// the captured value replaces the node when the closure is created.
+    Local {
+        depth: usize,
+        slot: usize,
+    },
+    /// Variable shared by its frame and the closures capturing it, read through, never a value
+    Cell(Rc<RefCell<N>>),
+    Throw(BN, Span),
+    /// Leave the function with the value
+    Return(BN, Span),
+    /// Leave the loop, it evaluates to the value
+    Break(BN, Span),
+    Continue(Span),
+    Try {
+        body: BN,
+        /// Function called with the error
+        catch: BN,
+    },
+    /// Template string, the parts are converted with `to_str` and joined
+    Concat(VN, Span),
+    /// Prefix `-`, `+` or `!`
+    Unary(Op, BN, Span),
+    Binary(Op, BN, BN, Span),
     //Terminal nodes, the following nodes can be output by eval
     FuncDef {
         args_name: Vec<ID>,
-        scope: BN,
+        /// Shared, so calls don't copy the body
+        scope: Rc<N>,
+        /// Capture a copy of the variables (`move`), instead of sharing them
+        by_value: bool,
     },
     FuncNativeDef(Native),
     Array(VN),
+    /// Keys are sorted
+    Object(BTreeMap<ID, N>),
+    /// Lazy numbers from `start` by `step`, up to `end` (included when `inclusive`)
+    Range {
+        start: f64,
+        end: f64,
+        step: f64,
+        inclusive: bool,
+    },
     Num(f64),
     Str(String),
+    Bool(bool),
     Unit,
 }
 
+/// Shape a value is matched against, binding variables
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    /// `_`, matches anything
+    Wildcard,
+    /// Matches anything, and binds it to the variable
+    Bind(ID),
+    /// Number, string, bool or unit literal, matches an equal value
+    Value(N),
+    /// `[a, b]`, matches an array of the same length, or longer with a rest
+    Array(Vec<Pattern>),
+    /// `..rest` in an array pattern, binds the elements left to the other patterns
+    Rest(Option<ID>),
+    /// `{x, y: pattern}`, matches an object having the fields
+    Object(Vec<(ID, Pattern)>),
+}
+
+impl Pattern {
+    /// Variables bound by the pattern, in the order they are declared
+    fn names(&self, names: &mut Vec<ID>) {
+        match self {
+            Pattern::Bind(name) | Pattern::Rest(Some(name)) => names.push(name.clone()),
+            Pattern::Array(items) => items.iter().for_each(|p| p.names(names)),
+            Pattern::Object(fields) => fields.iter().for_each(|(_, p)| p.names(names)),
+            _ => {}
+        }
+    }
+}
+
+/// Arm of a `match`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Arm {
+    pub pattern: Pattern,
+    pub guard: Option<N>,
+    pub body: N,
+}
+
 ///Native rust closure wrapper, to be inserted in the script
 #[derive(Clone)]
-pub struct Native(pub alloc::rc::Rc<dyn Fn(N, N, N, N) -> N>);
+pub struct Native(pub Rc<dyn Fn(N, N, N, N) -> N>);
+
+impl PartialEq for Native {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
 
 impl core::fmt::Debug for Native {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
@@ -57,27 +159,65 @@ impl core::fmt::Debug for Native {
     }
 }
 
-#[repr(u8)]
+/// Char range of a node, in the code it was parsed from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Copy)]
 pub enum Op {
-    Mul = 0,
-    Div = 1,
+    Mul,
+    Div,
     Equals,
     NotEquals,
     Lesser,
     Greater,
+    LesserEq,
+    GreaterEq,
     Modulus,
     And,
     Or,
+    /// `&&`, logical only, never a filter
+    And2,
+    /// `||`, logical only, never a reduce
+    Or2,
     Plus,
     Plus2,
+    /// `<<`, bitwise on integers, append in place to an array variable
     Shift,
+    ShiftRight,
+    Xor,
     Minus,
     Assign,
+    /// `..`, range without its end
+    Range,
+    /// `..=`, range with its end
+    RangeInclusive,
+    /// `step`, only after a range
+    Step,
+    /// Logical not, only unary
+    Not,
+    /// Bitwise not `~`, only unary
+    BitNot,
 }
 impl Op {
-    fn term_separate(self) -> bool {
-        self as u8 > 1
+    /// Binding power as a binary operator, higher binds tighter
+    fn precedence(self) -> u8 {
+        match self {
+            Op::Assign => 1,
+            Op::Or | Op::Or2 => 2,
+            Op::And | Op::And2 => 3,
+            Op::Equals | Op::NotEquals => 4,
+            Op::Lesser | Op::Greater | Op::LesserEq | Op::GreaterEq => 5,
+            Op::Range | Op::RangeInclusive | Op::Step => 6,
+            Op::Xor => 7,
+            Op::Shift | Op::ShiftRight => 8,
+            Op::Plus | Op::Plus2 | Op::Minus => 9,
+            Op::Mul | Op::Div | Op::Modulus => 10,
+            Op::Not | Op::BitNot => 11,
+        }
     }
 }
 
@@ -92,15 +232,93 @@ impl N {
     pub fn to_bool(&self) -> bool {
         match self {
             N::Num(x) if *x != 0.0 => true,
+            N::Bool(b) => *b,
             N::Str(s) => !s.is_empty(),
             N::Array(vec) => !vec.is_empty(),
+            N::Object(map) => !map.is_empty(),
+            N::Range { .. } => range_nth(self, 0).is_some(),
             _ => false,
         }
     }
+    /// Name of the value type, used in error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            N::FuncDef { .. } | N::FuncNativeDef(_) => "function",
+            N::Array(_) => "array",
+            N::Object(_) => "object",
+            N::Range { .. } => "range",
+            N::Num(_) => "number",
+            N::Str(_) => "string",
+            N::Bool(_) => "bool",
+            N::Unit => "unit",
+            _ => "expression",
+        }
+    }
+    /// Rough estimate of the heap memory owned by the node, in bytes
+    pub fn heap_size(&self) -> usize {
+        match self {
+            N::Str(s) => s.len(),
+            N::Array(v) | N::Block(v) => v
+                .iter()
+                .map(|e| core::mem::size_of::<N>() + e.heap_size())
+                .sum(),
+            N::FuncDef {
+                args_name, scope, ..
+            } => {
+                args_name
+                    .iter()
+                    .map(|a| core::mem::size_of::<ID>() + a.len())
+                    .sum::<usize>()
+                    + core::mem::size_of::<N>()
+                    + scope.heap_size()
+            }
+            N::Object(map) => map
+                .iter()
+                .map(|(k, v)| {
+                    core::mem::size_of::<ID>() + k.len() + core::mem::size_of::<N>() + v.heap_size()
+                })
+                .sum(),
+            N::Cell(cell) => cell.borrow().heap_size(),
+            _ => 0,
+        }
+    }
+
+    /// Location of the node in the code, when known
+    pub fn span(&self) -> Span {
+        match self {
+            N::Get(_, span)
+            | N::Binary(_, _, _, span)
+            | N::FuncCall { span, .. }
+            | N::Throw(_, span)
+            | N::Return(_, span)
+            | N::Break(_, span)
+            | N::Continue(span)
+            | N::Destructure(_, _, span)
+            | N::Concat(_, span)
+            | N::Unary(_, _, span) => *span,
+            _ => Span::default(),
+        }
+    }
+    pub fn is_func(&self) -> bool {
+        matches!(self, N::FuncDef { .. } | N::FuncNativeDef(_))
+    }
     pub fn to_str(&self) -> String {
         match self {
             N::Num(x) => format!("{}", x),
             N::Str(s) => s.clone(),
+            N::Bool(b) => format!("{}", b),
+            N::Range {
+                start,
+                end,
+                step,
+                inclusive,
+            } => {
+                let dots = if *inclusive { "..=" } else { ".." };
+                match step {
+                    1.0 => format!("{}{}{}", start, dots, end),
+                    _ => format!("{}{}{} step {}", start, dots, end, step),
+                }
+            }
             e => format!("{:?}", e),
         }
     }
@@ -119,14 +337,264 @@ enum Token {
     ParStart,
     ParEnd,
     While,
+    Throw,
+    Try,
+    Catch,
+    Move,
+    Return,
+    Break,
+    Continue,
+    For,
+    In,
+    Match,
     Quoted(String),
     Bin(Op),
     N(N),
     Let(ID),
-    Err(String),
+    /// `let` followed by a pattern
+    LetPattern,
+    Err(ParseError),
     Assoc,
     ArrayStart,
     ArrayEnd,
+    Colon,
+    Dot,
+    Not,
+    Tilde,
+}
+
+/// Error produced while parsing the code.
+///
+/// `pos` is a char offset into the code being parsed, `line` and `col` start at 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub pos: usize,
+    pub line: usize,
+    pub col: usize,
+    /// What the parser was looking for
+    pub expected: &'static str,
+    /// Source text of the offending token, `None` at the end of the code
+    pub found: Option<String>,
+    pub msg: &'static str,
+}
+
+impl ParseError {
+    /// Error on the token starting at (or after the whitespaces following) `i`
+    fn unexpected(code: &[char], i: usize, expected: &'static str, msg: &'static str) -> Self {
+        let mut start = i;
+        skip_whitespaces(&mut start, code);
+        let found = if start < code.len() {
+            let mut end = start;
+            next_token(&mut end, code);
+            Some(
+                code[start..end.clamp(start + 1, code.len())]
+                    .iter()
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        ParseError {
+            pos: start,
+            line: 0,
+            col: 0,
+            expected,
+            found,
+            msg,
+        }
+    }
+
+    /// Keep the error that went the furthest into the code, or reached `MAX_PARSE_DEPTH`
+    fn furthest(self, other: ParseError) -> Self {
+        if other.pos > self.pos || other.msg == TOO_DEEP {
+            other
+        } else {
+            self
+        }
+    }
+
+    /// Fill `line` and `col` from `pos`, and make them count from `origin`, the position of `code[0]`
+    fn locate(mut self, code: &[char], origin: &Origin) -> Self {
+        let at = origin.after(&code[..self.pos.min(code.len())]);
+        self.pos = at.pos;
+        self.line = at.line;
+        self.col = at.col;
+        self
+    }
+
+    /// True when the parser ran out of code, more input may fix it (REPL)
+    pub fn is_eof(&self) -> bool {
+        self.found.is_none()
+    }
+}
+
+impl core::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}, expected {}, found ",
+            self.line, self.col, self.msg, self.expected
+        )?;
+        match &self.found {
+            Some(found) => write!(f, "`{}`", found),
+            None => write!(f, "end of input"),
+        }
+    }
+}
+
+/// Error raised while evaluating, see [try_eval]
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    /// Location of the failing node
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeErrorKind {
+    UndefinedVariable(ID),
+    TypeMismatch {
+        op: Op,
+        lhs: &'static str,
+        rhs: &'static str,
+    },
+    /// Unary operator applied to a value it doesn't support
+    InvalidOperand {
+        op: Op,
+        operand: &'static str,
+    },
+    /// Call of a value that is not a function nor an array
+    NotCallable(&'static str),
+    /// `for` loop over a value that is not an array, a string, an object, a number or a range
+    NotIterable(&'static str),
+    IndexOutOfBounds {
+        index: isize,
+        len: usize,
+    },
+    /// Array indexed by something else than a number or a function,
+    /// object by something else than a string or a function
+    InvalidIndex {
+        target: &'static str,
+        index: &'static str,
+    },
+    /// Left side of `=` is not a variable
+    InvalidAssignment,
+    /// Value of a `throw` not caught by a `try`
+    Thrown(N),
+    /// `Ctx::fuel` reached 0
+    OutOfFuel,
+    /// `Ctx::deep` went past `Ctx::max_deep`, usually an infinite recursion,
+    /// or the tree-walker nested past `Ctx::max_nesting`. A `try` can't catch it
+    StackOverflow,
+    /// `Ctx::mem` would go past `Ctx::max_mem`
+    MemoryLimitExceeded,
+    /// The host raised `Ctx::interrupt`
+    Interrupted,
+    /// `return` on its way to its function, never reaches the host
+    Return(N),
+    /// `break` on its way to its loop, never reaches the host
+    Break(N),
+    /// `continue` on its way to its loop, never reaches the host
+    Continue,
+    /// `break` or `continue` outside of a loop, or leaving a function
+    OutsideLoop,
+    /// Value of a destructuring `let` (or function argument) not matching its pattern
+    PatternMismatch(&'static str),
+    /// The code doesn't parse, only returned by [try_parse_eval]
+    Parse(ParseError),
+}
+
+impl RuntimeErrorKind {
+    /// Can a script `try` catch this error
+    pub fn is_catchable(&self) -> bool {
+        !matches!(
+            self,
+            RuntimeErrorKind::OutOfFuel
+                | RuntimeErrorKind::StackOverflow
+                | RuntimeErrorKind::MemoryLimitExceeded
+                | RuntimeErrorKind::Interrupted
+                | RuntimeErrorKind::Return(_)
+                | RuntimeErrorKind::Break(_)
+                | RuntimeErrorKind::Continue
+        )
+    }
+}
+
+impl core::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{}..{}: {}", self.span.start, self.span.end, self.kind)
+    }
+}
+
+impl core::fmt::Display for RuntimeErrorKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            RuntimeErrorKind::UndefinedVariable(name) => write!(f, "undefined variable `{}`", name),
+            RuntimeErrorKind::TypeMismatch { op, lhs, rhs } => {
+                write!(f, "cannot apply {:?} to {} and {}", op, lhs, rhs)
+            }
+            RuntimeErrorKind::InvalidOperand { op, operand } => {
+                write!(f, "cannot apply {:?} to {}", op, operand)
+            }
+            RuntimeErrorKind::NotCallable(t) => write!(f, "{} is not callable", t),
+            RuntimeErrorKind::NotIterable(t) => write!(f, "{} is not iterable", t),
+            RuntimeErrorKind::IndexOutOfBounds { index, len } => {
+                write!(f, "index {} out of bounds for length {}", index, len)
+            }
+            RuntimeErrorKind::InvalidIndex { target, index } => {
+                write!(f, "cannot index an {} with {}", target, index)
+            }
+            RuntimeErrorKind::InvalidAssignment => write!(f, "cannot assign to this expression"),
+            RuntimeErrorKind::Thrown(n) => write!(f, "uncaught {}", n.to_str()),
+            RuntimeErrorKind::OutOfFuel => write!(f, "out of fuel"),
+            RuntimeErrorKind::StackOverflow => write!(f, "stack overflow"),
+            RuntimeErrorKind::MemoryLimitExceeded => write!(f, "memory limit exceeded"),
+            RuntimeErrorKind::Return(_) => write!(f, "`return` outside of a function"),
+            RuntimeErrorKind::Break(_)
+            | RuntimeErrorKind::Continue
+            | RuntimeErrorKind::OutsideLoop => {
+                write!(f, "`break` or `continue` outside of a loop")
+            }
+            RuntimeErrorKind::Interrupted => write!(f, "interrupted"),
+            RuntimeErrorKind::PatternMismatch(t) => write!(f, "{} doesn't match the pattern", t),
+            RuntimeErrorKind::Parse(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Position in all the code inserted into a [Ctx], `line` and `col` start at 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Origin {
+    pub pos: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Origin {
+    /// Position after the chars of `code`, starting from `self`
+    fn after(&self, code: &[char]) -> Origin {
+        let mut at = *self;
+        for c in code {
+            at.pos += 1;
+            if *c == '\n' {
+                at.line += 1;
+                at.col = 1;
+            } else {
+                at.col += 1;
+            }
+        }
+        at
+    }
+}
+
+impl Default for Origin {
+    fn default() -> Self {
+        Origin {
+            pos: 0,
+            line: 1,
+            col: 1,
+        }
+    }
 }
 
 /// Interpreter context, holds all state during execution.
@@ -134,7 +602,46 @@ pub struct Ctx {
     pub values: Vec<N>,
     pub idents: Vec<String>,
     pub code: Vec<char>,
+    /// Position of `code[0]` in all the code inserted so far, spans and parse errors count from the start
+    pub origin: Origin,
     pub deep: usize,
+    /// Maximum `deep`, the number of nested function calls, past it the evaluation stops
+    /// with `RuntimeErrorKind::StackOverflow`.
+    ///
+    /// The [vm::Vm] doesn't use the native stack for calls, the tree-walker is also limited by `max_nesting`.
+    pub max_deep: usize,
+    /// Maximum number of nested nodes the tree-walker evaluates at once, across function calls,
+    /// past it the evaluation stops with `RuntimeErrorKind::StackOverflow`.
+    ///
+    /// Each level costs up to 1.5KB of native stack in release builds, 5 times more in debug.
+    /// The default of 640 runs within 1MB in release, and lets 128 calls nest 5 nodes each.
+    pub max_nesting: usize,
+    /// Remaining number of nodes that can be evaluated, `None` for no limit.
+    ///
+    /// When it runs out, the evaluation stops with `RuntimeErrorKind::OutOfFuel`.
+    /// The interrupted expression can't be resumed (unless run by a [vm::Vm]),
+    /// but `Ctx` stays usable: refuel to evaluate the next expressions.
+    pub fuel: Option<usize>,
+    /// Estimated heap memory held by `values`, in bytes, see `N::heap_size`
+    pub mem: usize,
+    /// Maximum `mem`, `None` for no limit.
+    ///
+    /// Strings and arrays that would go past it stop the evaluation with
+    /// `RuntimeErrorKind::MemoryLimitExceeded` before being created.
+    pub max_mem: Option<usize>,
+    /// Flag raised by the host (from a native function, another thread of a single
+    /// core kernel, ...) to stop the evaluation with `RuntimeErrorKind::Interrupted`.
+    ///
+    /// Polled at every loop iteration and function call, lowered when the evaluation stops.
+    pub interrupt: Option<Rc<Cell<bool>>>,
+    /// Runtime errors are raised instead of evaluating to `N::Unit`
+    strict: bool,
+    /// Index in `values` of the first variable of the function being evaluated, see `N::Local`
+    frame: usize,
+    /// Number of loops around the node being evaluated, in its function
+    loops: usize,
+    /// Number of nodes the tree-walker is evaluating, see `max_nesting`
+    nesting: usize,
 }
 
 impl Ctx {
@@ -143,30 +650,107 @@ impl Ctx {
             values: Vec::new(),
             idents: Vec::new(),
             code: Vec::new(),
+            origin: Origin::default(),
             deep: 0,
+            max_deep: 128,
+            max_nesting: 640,
+            fuel: None,
+            mem: 0,
+            max_mem: None,
+            interrupt: None,
+            strict: false,
+            frame: 0,
+            loops: 0,
+            nesting: 0,
         }
     }
 
     #[inline(always)]
     pub fn drain(&mut self, from: usize) {
-        self.values.drain(from..);
+        if log::log_enabled!(log::Level::Info) {
+            for i in from..self.values.len() {
+                info!("forget {} {:?}:  {:?}", i, self.idents[i], self.values[i]);
+            }
+        }
+        for n in self.values.drain(from..) {
+            self.mem = self.mem.saturating_sub(n.heap_size());
+        }
         self.idents.drain(from..);
     }
 
+    /// Run `f`, then forget the variables it declared, even on error
+    #[inline(always)]
+    fn scope<T>(&mut self, f: impl FnOnce(&mut Ctx) -> T) -> T {
+        let from = self.values.len();
+        let res = f(self);
+        self.drain(from);
+        res
+    }
+
+    /// Run the loop `f`, `break` and `continue` inside it are not outside of a loop
+    #[inline(always)]
+    fn in_loop<T>(&mut self, f: impl FnOnce(&mut Ctx) -> T) -> T {
+        self.loops += 1;
+        let res = f(self);
+        self.loops -= 1;
+        res
+    }
+
+    /// Raise the error in strict mode, otherwise evaluate to `N::Unit`
+    fn fail(&self, kind: RuntimeErrorKind, span: Span) -> Result<N, RuntimeError> {
+        info!("runtime error {:?}", kind);
+        if self.strict {
+            Err(RuntimeError { kind, span })
+        } else {
+            Ok(N::Unit)
+        }
+    }
+
+    /// Stop if the host raised the `interrupt` flag, and lower it
+    #[inline(always)]
+    fn poll_interrupt(&self, span: Span) -> Result<(), RuntimeError> {
+        match &self.interrupt {
+            Some(flag) if flag.replace(false) => Err(RuntimeError {
+                kind: RuntimeErrorKind::Interrupted,
+                span,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that `bytes` more can be held without going over `max_mem`
+    fn reserve(&self, bytes: usize, span: Span) -> Result<(), RuntimeError> {
+        match self.max_mem {
+            Some(max) if self.mem + bytes > max => Err(RuntimeError {
+                kind: RuntimeErrorKind::MemoryLimitExceeded,
+                span,
+            }),
+            _ => Ok(()),
+        }
+    }
+
     #[inline(always)]
     pub fn set_val(&mut self, name: &str, n: N) {
+        self.mem += n.heap_size();
         self.idents.push(String::from(name));
         self.values.push(n);
     }
 
     #[inline(always)]
     pub fn set_val_absolute(&mut self, i: usize, name: &str, n: N) {
+        self.mem = (self.mem + n.heap_size()).saturating_sub(self.values[i].heap_size());
         self.idents[i] = String::from(name);
         self.values[i] = n;
     }
+    /// Variable of the function being evaluated, see `N::Local`
+    #[inline(always)]
+    fn local(&mut self, slot: usize) -> Option<&mut N> {
+        self.values.get_mut(self.frame + slot)
+    }
+
     /// Find a variable declared in the scope, or any parent scope
     ///
-    /// returns the path and the variable value
+    /// returns the path and the variable value, an `N::Cell` if a closure captured it
     pub fn find_var(&mut self, name: &str) -> Option<(usize, &mut N)> {
         for (i, id) in self.idents.iter().enumerate().rev() {
             if id == name {
@@ -181,12 +765,40 @@ impl Ctx {
         self.code.extend(code.chars());
     }
 
-    pub fn parse_next_expr(&mut self) -> Result<N, Error> {
+    /// Parse the next expression, and remove its code from `self.code`.
+    ///
+    /// On error, the code is left untouched. Spans and error positions count from the start of
+    /// all the code inserted, see `origin`.
+    pub fn parse_next_expr(&mut self) -> Result<N, ParseError> {
         let mut i = 0;
-        let res = parse_expr(&mut i, &self.code, 0)?;
+        let mut res =
+            parse_expr(&mut i, &self.code, 0).map_err(|e| e.locate(&self.code, &self.origin))?;
+        self.origin = self.origin.after(&self.code[..i]);
         self.code.drain(0..i);
+        offset_spans(&mut res, self.origin.pos - i);
+        resolve(&mut res, &mut alloc::vec![Frame::default()], true);
         Ok(res)
     }
+
+    /// Like [Ctx::parse_next_expr], but `None` when only whitespace and comments are left,
+    /// and errors as [RuntimeErrorKind::Parse]
+    pub(crate) fn next_expr(&mut self) -> Result<Option<N>, RuntimeError> {
+        let mut i = 0;
+        // An unterminated comment is left to the parser to report
+        if skip_trivia(&mut i, &self.code, &mut |_| {}).is_ok() && i == self.code.len() {
+            return Ok(None);
+        }
+        match self.parse_next_expr() {
+            Ok(n) => Ok(Some(n)),
+            Err(e) => Err(RuntimeError {
+                span: Span {
+                    start: e.pos,
+                    end: e.pos,
+                },
+                kind: RuntimeErrorKind::Parse(e),
+            }),
+        }
+    }
 }
 
 impl Default for Ctx {
@@ -195,282 +807,1625 @@ impl Default for Ctx {
     }
 }
 
-pub fn parse_eval(code: &str) -> N {
+/// Parse and evaluate all the expressions of `code`, with a new [Ctx], and return the last value
+///
+/// Like [eval], runtime errors evaluate to `N::Unit` and an uncaught `throw` is returned.
+/// The evaluation stops at the first parse error, see [try_parse_eval] to get it.
+pub fn parse_eval(code: &str) -> Result<N, RuntimeError> {
     let mut ctx = Ctx::new();
     ctx.insert_code(code);
     let mut res = N::Unit;
-    while let Ok(mut parent) = ctx.parse_next_expr() {
-        res = eval(&mut parent, &mut ctx);
+    while let Ok(parent) = ctx.parse_next_expr() {
+        res = eval(&parent, &mut ctx)?;
     }
-    res
+    Ok(res)
+}
+
+/// Like [parse_eval], but runtime errors and uncaught `throw` are returned, see [try_eval],
+/// so are parse errors as [RuntimeErrorKind::Parse]
+pub fn try_parse_eval(code: &str) -> Result<N, RuntimeError> {
+    let mut ctx = Ctx::new();
+    ctx.insert_code(code);
+    let mut res = N::Unit;
+    while let Some(parent) = ctx.next_expr()? {
+        res = try_eval(&parent, &mut ctx)?;
+    }
+    Ok(res)
+}
+
+/// Order of numbers, strings (lexicographic) and arrays (element-wise), `None` for other values
+fn compare(l: &N, r: &N) -> Option<Ordering> {
+    match (l, r) {
+        (N::Num(l), N::Num(r)) => l.partial_cmp(r),
+        (N::Str(l), N::Str(r)) => Some(l.cmp(r)),
+        (N::Array(l), N::Array(r)) => {
+            for (l, r) in l.iter().zip(r) {
+                match compare(l, r)? {
+                    Ordering::Equal => {}
+                    ord => return Some(ord),
+                }
+            }
+            Some(l.len().cmp(&r.len()))
+        }
+        _ => None,
+    }
+}
+
+/// The number has no fractional part, and fits in an `i64`
+fn is_int(x: f64) -> bool {
+    x as i64 as f64 == x
+}
+
+/// Index counted from the end when negative, `-1` is the last element
+fn from_end(i: f64, len: usize) -> f64 {
+    if i < 0.0 {
+        i + len as f64
+    } else {
+        i
+    }
+}
+
+/// `n`-th number of a range, if it has one
+fn range_nth(range: &N, n: usize) -> Option<f64> {
+    let N::Range {
+        start,
+        end,
+        step,
+        inclusive,
+    } = *range
+    else {
+        return None;
+    };
+    let x = start + n as f64 * step;
+    let inside = match step.partial_cmp(&0.0)? {
+        Ordering::Greater => x < end || (inclusive && x == end),
+        Ordering::Less => x > end || (inclusive && x == end),
+        // Would never end
+        Ordering::Equal => false,
+    };
+    inside.then_some(x)
+}
+
+/// Indexes of the elements of a collection taken by a range, out of bounds ones are skipped
+///
+/// `None` when the step is not a whole number, it would take elements more than once.
+fn slice(range: &N, len: usize) -> Option<Vec<usize>> {
+    let N::Range {
+        start,
+        end,
+        step,
+        inclusive,
+    } = *range
+    else {
+        return None;
+    };
+    if !is_int(step) {
+        return None;
+    }
+    let (start, end) = (from_end(start, len), from_end(end, len));
+    let last = len as f64 - 1.0;
+    let clamped = if step > 0.0 {
+        N::Range {
+            start: start.max(0.0),
+            end: end.min(last),
+            step,
+            inclusive: inclusive || end > last,
+        }
+    } else {
+        N::Range {
+            start: start.min(last),
+            end: end.max(0.0),
+            step,
+            inclusive: inclusive || end < 0.0,
+        }
+    };
+    Some(
+        (0..)
+            .map_while(|n| range_nth(&clamped, n))
+            .map(|x| x as usize)
+            .collect(),
+    )
 }
 
+/// Index type name of a range that can't slice
+const FRACTIONAL_STEP: &str = "range with a fractional step";
+
 fn bool_n(b: bool) -> N {
-    N::Num(if b { 1.0 } else { 0.0 })
+    N::Bool(b)
 }
 
 ///Interprets the node using the ctx/interpreter provided
-pub fn eval(n: &N, ctx: &mut Ctx) -> N {
-    ctx.deep += 1;
-    if log::log_enabled!(log::Level::Info) {
-        info!("\n{}eval {:?}", pa(ctx.deep), n);
-        for i in 0..ctx.idents.len() {
-            info!(
-                "{}{} {:?}:{:?}",
-                pa(ctx.deep),
-                i,
-                ctx.idents[i],
-                ctx.values[i]
-            );
+///
+/// Runtime errors evaluate to `N::Unit`, see [try_eval] to get them.
+/// An uncaught `throw` and the limits (fuel, memory, stack overflow, interruption)
+/// stop the evaluation and are returned.
+pub fn eval(n: &N, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    eval_root(n, false, ctx)
+}
+
+///Interprets the node like [eval], but stops at the first runtime error
+pub fn try_eval(n: &N, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    eval_root(n, true, ctx)
+}
+
+/// Evaluate a whole expression. When it stops on an error, the variables it declared
+/// are forgotten, and `OutOfFuel` has the span of the expression: where the fuel runs out
+/// depends on the backend.
+fn eval_root(n: &N, strict: bool, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    let strict = core::mem::replace(&mut ctx.strict, strict);
+    let values = ctx.values.len();
+    let frame = core::mem::replace(&mut ctx.frame, values);
+    let loops = core::mem::replace(&mut ctx.loops, 0);
+    let res = eval_node(n, ctx);
+    let res = function_result(res, ctx);
+    ctx.strict = strict;
+    ctx.frame = frame;
+    ctx.loops = loops;
+    res.map_err(|mut e| {
+        ctx.drain(values);
+        if e.kind == RuntimeErrorKind::OutOfFuel {
+            e.span = n.span();
+        }
+        e
+    })
+}
+
+fn eval_node(n: &N, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    if let Some(fuel) = ctx.fuel.as_mut() {
+        if *fuel == 0 {
+            return Err(RuntimeError {
+                kind: RuntimeErrorKind::OutOfFuel,
+                span: n.span(),
+            });
         }
+        *fuel -= 1;
+    }
+    if log::log_enabled!(log::Level::Info) {
+        log_eval(n, ctx);
     }
-    let res = match n {
+    if ctx.nesting >= ctx.max_nesting {
+        return Err(RuntimeError {
+            kind: RuntimeErrorKind::StackOverflow,
+            span: n.span(),
+        });
+    }
+    ctx.nesting += 1;
+    let res = eval_match(n, ctx);
+    ctx.nesting -= 1;
+    res
+}
+
+/// Out of `eval_node`, its formatting would grow the stack frame of every evaluation
+#[cold]
+fn log_eval(n: &N, ctx: &Ctx) {
+    info!("\n{}eval {:?}", pa(ctx.deep), n);
+    for i in 0..ctx.idents.len() {
+        info!(
+            "{}{} {:?}:{:?}",
+            pa(ctx.deep),
+            i,
+            ctx.idents[i],
+            ctx.values[i]
+        );
+    }
+}
+
+fn eval_match(n: &N, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    // Arms only dispatch, each temporary costs stack space in debug builds
+    match n {
         N::If {
             condition,
             path_true,
             path_false,
-        } => match eval(condition, ctx).to_bool() {
-            true => eval(path_true, ctx),
-            false => eval(path_false, ctx),
+        } => eval_if(condition, path_true, path_false, ctx),
+        N::While { condition, body } => ctx.in_loop(|ctx| eval_while(condition, body, ctx)),
+        N::For { vars, iter, body } => eval_for(vars, iter, body, ctx),
+        N::Match { value, arms } => eval_arms(value, arms, ctx),
+        N::Destructure(pattern, val, span) => eval_destructure(pattern, val, *span, ctx),
+        N::Block(arr) => eval_block(arr, ctx),
+        N::Set(..) | N::Get(..) | N::Local { .. } | N::Cell(_) => eval_var(n, ctx),
+        N::FuncCall { func, args, span } => eval_call(func, args, *span, ctx),
+        N::Throw(..) | N::Return(..) | N::Break(..) | N::Continue(_) => eval_escape(n, ctx),
+        N::Try { body, catch } => eval_try(body, catch, ctx),
+        N::Binary(Op::Assign, l, r, span) => eval_assign(l, r, *span, ctx),
+        N::Binary(Op::Shift, l, r, span) => eval_shift(l, r, *span, ctx),
+        N::Binary(..) | N::Unary(..) => eval_op(n, ctx),
+        N::FuncDef { .. } | N::Array(_) | N::Object(_) | N::Concat(..) => eval_literal(n, ctx),
+        e => {
+            info!("noop");
+            Ok(e.clone())
+        }
+    }
+}
+
+// Out of `eval_match`, to keep its stack frame small
+
+fn eval_if(condition: &N, path_true: &N, path_false: &N, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    match eval_node(condition, ctx)?.to_bool() {
+        true => eval_node(path_true, ctx),
+        false => eval_node(path_false, ctx),
+    }
+}
+
+fn eval_block(arr: &[N], ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    ctx.scope(|ctx| arr.iter().try_fold(N::Unit, |_, a| eval_node(a, ctx)))
+}
+
+fn eval_var(n: &N, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    match n {
+        N::Set(name, val) => {
+            let val = eval_node(val, ctx)?;
+            ctx.set_val(name, val);
+            Ok(N::Unit)
+        }
+        N::Get(name, span) => match ctx.find_var(name) {
+            Some((_, v)) => Ok(load(v)),
+            None => ctx.fail(RuntimeErrorKind::UndefinedVariable(name.clone()), *span),
         },
-        N::While { condition, body } => {
-            let mut res = N::Unit;
-            while eval(condition, ctx).to_bool() {
-                res = eval(body, ctx)
+        N::Local { depth: 0, slot } => Ok(ctx.local(*slot).map_or(N::Unit, |v| load(v))),
+        N::Cell(cell) => Ok(cell.borrow().clone()),
+        // Captured when the closure was created
+        _ => Ok(N::Unit),
+    }
+}
+
+fn eval_destructure(p: &Pattern, val: &N, span: Span, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    let val = eval_node(val, ctx)?;
+    destructure(p, val, span, ctx)
+}
+
+fn eval_while(condition: &N, body: &N, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    let mut res = N::Unit;
+    loop {
+        let turn = match eval_node(condition, ctx) {
+            Ok(c) if !c.to_bool() => return Ok(res),
+            Ok(_) => {
+                ctx.poll_interrupt(condition.span())?;
+                eval_node(body, ctx)
             }
-            res
+            Err(e) => Err(e),
+        };
+        match loop_turn(turn)? {
+            ControlFlow::Continue(v) => res = v,
+            ControlFlow::Break(v) => return Ok(v),
         }
-        N::Block(arr) => {
-            let variable_skip_begin = ctx.values.len();
-            let mut res = N::Unit;
-            for a in arr.iter() {
-                res = eval(a, ctx);
-            }
-            if log::log_enabled!(log::Level::Info) {
-                for i in variable_skip_begin..ctx.values.len() {
-                    info!(
-                        "block forget {} {:?}:  {:?}",
-                        i, ctx.idents[i], ctx.values[i]
-                    );
+    }
+}
+
+fn eval_for(vars: &[ID], iter: &N, body: &N, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    let mut cursor = match Cursor::new(eval_node(iter, ctx)?) {
+        Ok(cursor) => cursor,
+        Err(kind) => return ctx.fail(kind, iter.span()),
+    };
+    ctx.in_loop(|ctx| {
+        let mut res = N::Unit;
+        while let Some((item, key)) = cursor.next() {
+            ctx.poll_interrupt(iter.span())?;
+            let turn = ctx.scope(|ctx| {
+                for (name, v) in vars.iter().zip([item, key]) {
+                    ctx.set_val(name, v);
                 }
+                eval_node(body, ctx)
+            });
+            match loop_turn(turn)? {
+                ControlFlow::Continue(v) => res = v,
+                ControlFlow::Break(v) => return Ok(v),
             }
-            ctx.drain(variable_skip_begin);
-            res
         }
-        N::Set(name, val) => {
-            let val = eval(val, ctx);
-            ctx.set_val(name, val);
-            N::Unit
-        }
-        N::Get(name) => ctx.find_var(name).map(|e| e.1.clone()).unwrap_or(N::Unit),
-        N::FuncCall { func, args } => match eval(func, ctx) {
-            N::FuncNativeDef(native) => native.0(
-                args.first().map(|e| eval(e, ctx)).unwrap_or(N::Unit),
-                args.get(1).map(|e| eval(e, ctx)).unwrap_or(N::Unit),
-                args.get(2).map(|e| eval(e, ctx)).unwrap_or(N::Unit),
-                args.get(3).map(|e| eval(e, ctx)).unwrap_or(N::Unit),
-            ),
-            N::FuncDef {
-                args_name,
-                mut scope,
-            } => {
-                let variable_scope_index = ctx.values.len();
-                for (i, arg_name) in args_name.iter().enumerate() {
-                    let val = args.get(i).map(|e| eval(e, ctx)).unwrap_or(N::Unit);
-                    ctx.set_val(arg_name, val);
-                }
-                let res = eval(&mut scope, ctx);
-                if log::log_enabled!(log::Level::Info) {
-                    for i in variable_scope_index..ctx.values.len() {
-                        info!("forget {} {:?}:  {:?}", i, ctx.idents[i], ctx.values[i]);
-                    }
-                }
-                ctx.drain(variable_scope_index);
-                res
-            }
-            N::Array(mut v) => {
-                if let Some(index) = args.first().map(|e| eval(e, ctx)) {
-                    match index {
-                        N::Num(i) => {
-                            let mut i = i as isize;
-                            if i < 0 {
-                                i = v.len() as isize + (i as isize);
-                            }
-                            v.get(i as usize).cloned().unwrap_or(N::Unit)
-                        }
-                        N::FuncDef { args_name, scope } => {
-                            for (index, e) in v.iter_mut().enumerate() {
-                                let variable_scope_index = ctx.values.len();
-                                if let Some(s) = args_name.first() {
-                                    ctx.set_val(s, e.clone());
-                                }
-                                if let Some(s) = args_name.get(1) {
-                                    ctx.set_val(s, N::Num(index as f64));
-                                }
-                                *e = eval(&scope, ctx);
-                                ctx.drain(variable_scope_index);
-                            }
-                            N::Array(v)
-                        }
-                        _ => N::Unit,
-                    }
-                } else {
-                    N::Num(v.len() as f64)
-                }
+        Ok(res)
+    })
+}
+
+fn eval_arms(value: &N, arms: &[Arm], ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    let value = eval_node(value, ctx)?;
+    for arm in arms {
+        let res = ctx.scope(|ctx| {
+            if !bind(&arm.pattern, &value, ctx) {
+                return Ok(None);
             }
-            _ => N::Unit,
-        },
-        N::Binary(op, l, r) => {
-            if let Op::Assign = op {
-                if let N::Get(name) = l.as_ref() {
-                    if let Some((key, _)) = ctx.find_var(name) {
-                        let v = eval(r, ctx);
-                        ctx.set_val_absolute(key, name, v);
-                    }
-                }
-                return N::Unit;
-            }
-            let lt = eval(l, ctx);
-            let rt = eval(r, ctx);
-            match (op.clone(), &lt, &rt) {
-                (Op::Plus, N::Num(li), N::Num(ri)) => N::Num(li + ri),
-                (Op::Plus, N::Num(li), N::Unit) => N::Num(*li),
-                (Op::Plus, N::Unit, N::Num(ri)) => N::Num(*ri),
-                (Op::Greater, N::Num(li), N::Num(ri)) => bool_n(li > ri),
-                (Op::Lesser, N::Num(li), N::Num(ri)) => bool_n(li < ri),
-                (Op::Equals, N::Num(li), N::Num(ri)) => bool_n(li == ri),
-                (Op::Equals, N::Str(li), N::Str(ri)) => bool_n(li == ri),
-                (Op::NotEquals, N::Num(li), N::Num(ri)) => bool_n(li != ri),
-                (Op::NotEquals, N::Str(li), N::Str(ri)) => bool_n(li != ri),
-                (Op::Minus, N::Num(li), N::Num(ri)) => N::Num(li - ri),
-                (Op::Mul, N::Num(li), N::Num(ri)) => N::Num(li * ri),
-                (Op::Div, N::Num(li), N::Num(ri)) => N::Num(li / ri),
-                (Op::Modulus, N::Num(li), N::Num(ri)) => N::Num(li.rem(ri)),
-                (Op::Plus, N::Str(li), ri) => N::Str(format!("{}{}", li, ri.to_str())),
-                (Op::Plus, li, N::Str(ri)) => N::Str(format!("{}{}", li.to_str(), ri)),
-                (Op::Plus2, N::Array(li), N::Array(ri)) => {
-                    N::Array(li.iter().chain(ri).cloned().collect())
-                }
-                (Op::Plus, N::Array(li), ri) => {
-                    N::Array(li.iter().chain(core::iter::once(ri)).cloned().collect())
-                }
-                (Op::Plus, li, N::Array(ri)) => {
-                    N::Array(core::iter::once(li).chain(ri.iter()).cloned().collect())
-                }
-                (Op::And, N::Array(li), N::FuncDef { args_name, scope }) => {
-                    let mut new_arr = Vec::new();
-                    for (index, e) in li.iter().enumerate() {
-                        let variable_scope_index = ctx.values.len();
-                        if let Some(s) = args_name.first() {
-                            ctx.set_val(s, e.clone());
-                        }
-                        if let Some(s) = args_name.get(1) {
-                            ctx.set_val(s, N::Num(index as f64));
-                        }
-                        if eval(&scope, ctx).to_bool() {
-                            new_arr.push(e.clone());
-                        }
-                        ctx.drain(variable_scope_index);
-                    }
-                    N::Array(new_arr)
+            if let Some(guard) = &arm.guard {
+                if !eval_node(guard, ctx)?.to_bool() {
+                    return Ok(None);
                 }
-                (Op::Or, N::Array(li), N::FuncDef { args_name, scope }) => {
-                    let mut acc = li.first().cloned().unwrap_or(N::Unit);
+            }
+            eval_node(&arm.body, ctx).map(Some)
+        })?;
+        if let Some(res) = res {
+            return Ok(res);
+        }
+    }
+    Ok(N::Unit)
+}
 
-                    for e in li.iter().skip(1) {
-                        let variable_scope_index = ctx.values.len();
-                        if let Some(s) = args_name.first() {
-                            ctx.set_val(s, acc);
-                        }
-                        if let Some(s) = args_name.get(1) {
-                            ctx.set_val(s, e.clone());
-                        }
-                        acc = eval(&scope, ctx);
-                        ctx.drain(variable_scope_index);
-                    }
-                    acc
+/// Declare the variables of the pattern, all `N::Unit` when the value doesn't match
+fn destructure(p: &Pattern, v: N, span: Span, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    let from = ctx.values.len();
+    if !bind(p, &v, ctx) {
+        ctx.drain(from);
+        ctx.fail(RuntimeErrorKind::PatternMismatch(v.type_name()), span)?;
+        let mut names = Vec::new();
+        p.names(&mut names);
+        for name in names {
+            ctx.set_val(&name, N::Unit);
+        }
+    }
+    Ok(N::Unit)
+}
+
+/// Match `v` against the pattern, declaring its variables, false when it doesn't match
+///
+/// The variables declared before a mismatch are left to the caller to forget.
+fn bind(p: &Pattern, v: &N, ctx: &mut Ctx) -> bool {
+    match (p, v) {
+        (Pattern::Wildcard, _) | (Pattern::Rest(None), _) => true,
+        (Pattern::Bind(name), v) | (Pattern::Rest(Some(name)), v) => {
+            ctx.set_val(name, v.clone());
+            true
+        }
+        (Pattern::Value(x), v) => x == v,
+        (Pattern::Array(items), N::Array(arr)) => {
+            let Some(rest) = items.iter().position(|p| matches!(p, Pattern::Rest(_))) else {
+                return items.len() == arr.len()
+                    && items.iter().zip(arr).all(|(p, v)| bind(p, v, ctx));
+            };
+            if arr.len() + 1 < items.len() {
+                return false;
+            }
+            let tail = arr.len() + rest + 1 - items.len();
+            items[..rest].iter().zip(arr).all(|(p, v)| bind(p, v, ctx))
+                && bind(&items[rest], &N::Array(arr[rest..tail].to_vec()), ctx)
+                && items[rest + 1..]
+                    .iter()
+                    .zip(&arr[tail..])
+                    .all(|(p, v)| bind(p, v, ctx))
+        }
+        (Pattern::Object(fields), N::Object(map)) => fields
+            .iter()
+            .all(|(key, p)| map.get(key).is_some_and(|v| bind(p, v, ctx))),
+        _ => false,
+    }
+}
+
+/// Value of a loop turn, `Break` when a `break` ends the loop
+fn loop_turn(res: Result<N, RuntimeError>) -> Result<ControlFlow<N, N>, RuntimeError> {
+    match res {
+        Ok(v) => Ok(ControlFlow::Continue(v)),
+        Err(RuntimeError {
+            kind: RuntimeErrorKind::Break(v),
+            ..
+        }) => Ok(ControlFlow::Break(v)),
+        Err(RuntimeError {
+            kind: RuntimeErrorKind::Continue,
+            ..
+        }) => Ok(ControlFlow::Continue(N::Unit)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Progress of a `for` loop through the value it iterates
+struct Cursor {
+    items: N,
+    /// Keys of an object, its values are in `items`
+    keys: Vec<ID>,
+    /// Byte offset of the next char of a string
+    pos: usize,
+    index: usize,
+}
+
+impl Cursor {
+    fn new(n: N) -> Result<Cursor, RuntimeErrorKind> {
+        let (items, keys) = match n {
+            N::Object(map) => {
+                let (keys, values) = map.into_iter().unzip();
+                (N::Array(values), keys)
+            }
+            N::Array(_) | N::Str(_) | N::Num(_) | N::Range { .. } => (n, Vec::new()),
+            n => return Err(RuntimeErrorKind::NotIterable(n.type_name())),
+        };
+        Ok(Cursor {
+            items,
+            keys,
+            pos: 0,
+            index: 0,
+        })
+    }
+
+    /// Next element, with its index (or key)
+    fn next(&mut self) -> Option<(N, N)> {
+        let item = match &self.items {
+            N::Array(arr) => arr.get(self.index)?.clone(),
+            N::Str(s) => {
+                let c = s[self.pos..].chars().next()?;
+                self.pos += c.len_utf8();
+                N::Str(String::from(c))
+            }
+            // Counts from 0
+            N::Num(n) if (self.index as f64) < *n => N::Num(self.index as f64),
+            range @ N::Range { .. } => N::Num(range_nth(range, self.index)?),
+            _ => return None,
+        };
+        let key = match self.keys.get(self.index) {
+            Some(key) => N::Str(key.clone()),
+            None => N::Num(self.index as f64),
+        };
+        self.index += 1;
+        Some((item, key))
+    }
+}
+
+/// Leave with an error, caught by a `try`, a loop or a function
+fn eval_escape(n: &N, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    let (kind, span) = match n {
+        N::Throw(val, span) => (RuntimeErrorKind::Thrown(eval_node(val, ctx)?), *span),
+        N::Return(val, span) => (RuntimeErrorKind::Return(eval_node(val, ctx)?), *span),
+        N::Break(val, span) => (RuntimeErrorKind::Break(eval_node(val, ctx)?), *span),
+        N::Continue(span) => (RuntimeErrorKind::Continue, *span),
+        _ => return Ok(N::Unit),
+    };
+    // Raised right away, a `try` around can catch it. Otherwise the function returns unit
+    if ctx.strict && ctx.loops == 0 && matches!(n, N::Break(..) | N::Continue(_)) {
+        return ctx.fail(RuntimeErrorKind::OutsideLoop, span);
+    }
+    Err(RuntimeError { kind, span })
+}
+
+/// Result of a function body: stops a `return`, a `break` or `continue` can't leave it
+fn function_result(res: Result<N, RuntimeError>, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    match res {
+        Err(RuntimeError {
+            kind: RuntimeErrorKind::Return(v),
+            ..
+        }) => Ok(v),
+        Err(RuntimeError {
+            kind: RuntimeErrorKind::Break(_) | RuntimeErrorKind::Continue,
+            span,
+        }) => ctx.fail(RuntimeErrorKind::OutsideLoop, span),
+        res => res,
+    }
+}
+
+fn eval_try(body: &N, catch: &N, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    let strict = core::mem::replace(&mut ctx.strict, true);
+    let values = ctx.values.len();
+    let res = eval_node(body, ctx);
+    ctx.strict = strict;
+    match res {
+        Ok(res) => Ok(res),
+        Err(e) if !e.kind.is_catchable() => Err(e),
+        Err(RuntimeError { kind, span }) => {
+            // Like the whole expression, the body failed: forget what it declared
+            ctx.drain(values);
+            let err = match kind {
+                RuntimeErrorKind::Thrown(val) => val,
+                kind => N::Str(format!("{}", kind)),
+            };
+            let handler = eval_node(catch, ctx)?;
+            call(&handler, alloc::vec![err], span, ctx)
+        }
+    }
+}
+
+fn eval_call(func: &N, args: &[N], span: Span, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    ctx.poll_interrupt(span)?;
+    let f = eval_node(func, ctx)?;
+    let args = args
+        .iter()
+        .map(|e| eval_node(e, ctx))
+        .collect::<Result<Vec<_>, _>>()?;
+    call(&f, args, span, ctx)
+}
+
+fn eval_op(n: &N, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    match n {
+        N::Binary(op, l, r, span) => {
+            let lt = eval_node(l, ctx)?;
+            if let Some(res) = short_circuit(*op, &lt) {
+                return Ok(res);
+            }
+            let rt = eval_node(r, ctx)?;
+            binary(*op, lt, rt, *span, ctx)
+        }
+        N::Unary(op, val, span) => {
+            let val = eval_node(val, ctx)?;
+            unary(*op, val, *span, ctx)
+        }
+        e => Ok(e.clone()),
+    }
+}
+
+fn eval_literal(n: &N, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    match n {
+        N::FuncDef {
+            args_name,
+            scope,
+            by_value,
+        } => Ok(N::FuncDef {
+            args_name: args_name.clone(),
+            scope: Rc::new(dup(&mut args_name.clone(), scope, 1, *by_value, ctx)),
+            by_value: *by_value,
+        }),
+        N::Array(v) => {
+            let arr = N::Array(
+                v.iter()
+                    .map(|e| eval_node(e, ctx))
+                    .collect::<Result<_, _>>()?,
+            );
+            ctx.reserve(arr.heap_size(), n.span())?;
+            Ok(arr)
+        }
+        N::Object(map) => {
+            let obj = N::Object(
+                map.iter()
+                    .map(|(k, e)| Ok((k.clone(), eval_node(e, ctx)?)))
+                    .collect::<Result<_, _>>()?,
+            );
+            ctx.reserve(obj.heap_size(), n.span())?;
+            Ok(obj)
+        }
+        N::Concat(parts, span) => {
+            let mut s = String::new();
+            for e in parts {
+                s.push_str(&eval_node(e, ctx)?.to_str());
+            }
+            ctx.reserve(s.len(), *span)?;
+            Ok(N::Str(s))
+        }
+        e => Ok(e.clone()),
+    }
+}
+
+/// `<<` appends in place when the left side is a variable holding an array,
+/// it is a bitwise shift otherwise
+fn eval_shift(l: &N, r: &N, span: Span, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    if !appends(l, ctx) {
+        let lt = eval_node(l, ctx)?;
+        let rt = eval_node(r, ctx)?;
+        return binary(Op::Shift, lt, rt, span, ctx);
+    }
+    // Reading a variable has no side effect, only `r` is evaluated
+    let rt = eval_node(r, ctx)?;
+    append_var(l, rt, span, ctx)
+}
+
+/// True when the variable `l` holds an array, `<<` appends to it
+fn appends(l: &N, ctx: &mut Ctx) -> bool {
+    let mut cell = N::Unit;
+    variable(l, ctx, &mut cell).is_some_and(|var| is_array(var))
+}
+
+/// Append `rt` to the array held by the variable `l`, after `r` was evaluated
+fn append_var(l: &N, rt: N, span: Span, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    if !appends(l, ctx) {
+        // `r` assigned something else to the variable
+        let lt = eval_var(l, ctx)?;
+        return binary(Op::Shift, lt, rt, span, ctx);
+    }
+    let mut cell = N::Unit;
+    let size = core::mem::size_of::<N>() + rt.heap_size();
+    ctx.reserve(size, span)?;
+    if let Some(var) = variable(l, ctx, &mut cell) {
+        append(var, rt);
+    }
+    ctx.mem += size;
+    Ok(N::Unit)
+}
+
+/// Variable named by `l`, a captured cell is cloned into `cell` to be reached
+fn variable<'a>(l: &N, ctx: &'a mut Ctx, cell: &'a mut N) -> Option<&'a mut N> {
+    match l {
+        N::Get(name, _) => ctx.find_var(name).map(|(_, v)| v),
+        N::Local { depth: 0, slot } => ctx.local(*slot),
+        N::Cell(_) => {
+            *cell = l.clone();
+            Some(cell)
+        }
+        _ => None,
+    }
+}
+
+fn is_array(var: &N) -> bool {
+    match var {
+        N::Array(_) => true,
+        N::Cell(cell) => is_array(&cell.borrow()),
+        _ => false,
+    }
+}
+
+fn append(var: &mut N, v: N) {
+    match var {
+        N::Array(arr) => arr.push(v),
+        N::Cell(cell) => append(&mut cell.borrow_mut(), v),
+        _ => {}
+    }
+}
+
+fn eval_assign(l: &N, r: &N, span: Span, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    match l {
+        N::Get(name, span) => {
+            let v = eval_node(r, ctx)?;
+            assign(name, v, *span, ctx)
+        }
+        N::Local { depth: 0, slot } => {
+            let v = eval_node(r, ctx)?;
+            Ok(assign_local(*slot, v, ctx))
+        }
+        N::Cell(cell) => {
+            let v = eval_node(r, ctx)?;
+            Ok(assign_cell(cell, v, ctx))
+        }
+        N::FuncCall { .. } => {
+            let v = eval_node(r, ctx)?;
+            assign_element(l, v, span, ctx)
+        }
+        _ => ctx.fail(RuntimeErrorKind::InvalidAssignment, span),
+    }
+}
+
+/// Give a new value to an element of the arrays and objects held by a variable, like `o.a(0) = v`
+fn assign_element(place: &N, v: N, span: Span, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    let mut keys = Vec::new();
+    let mut var = place;
+    while let N::FuncCall { func, args, .. } = var {
+        match args.as_slice() {
+            [key] => keys.push(eval_node(key, ctx)?),
+            _ => return ctx.fail(RuntimeErrorKind::InvalidAssignment, span),
+        }
+        var = func;
+    }
+    keys.reverse();
+    store_element(var, &keys, v, span, ctx)
+}
+
+/// Store `v` at the end of the `keys` path in the variable `var`
+fn store_element(var: &N, keys: &[N], v: N, span: Span, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    let size = v.heap_size();
+    let res = match var {
+        N::Get(name, span) => match ctx.find_var(name) {
+            Some((_, var)) => set_element(var, keys, v),
+            None => return ctx.fail(RuntimeErrorKind::UndefinedVariable(name.clone()), *span),
+        },
+        N::Local { depth: 0, slot } => match ctx.local(*slot) {
+            Some(var) => set_element(var, keys, v),
+            None => Ok(N::Unit),
+        },
+        N::Cell(cell) => set_element(&mut cell.borrow_mut(), keys, v),
+        _ => return ctx.fail(RuntimeErrorKind::InvalidAssignment, span),
+    };
+    match res {
+        Ok(old) => {
+            ctx.mem = (ctx.mem + size).saturating_sub(old.heap_size());
+            Ok(N::Unit)
+        }
+        Err(kind) => ctx.fail(kind, span),
+    }
+}
+
+/// Store `v` at the end of the `keys` path, returns the old value
+fn set_element(var: &mut N, keys: &[N], v: N) -> Result<N, RuntimeErrorKind> {
+    let Some((key, rest)) = keys.split_first() else {
+        return Ok(store(var, v));
+    };
+    match (var, key) {
+        (N::Cell(cell), _) => set_element(&mut cell.borrow_mut(), keys, v),
+        (N::Array(arr), N::Num(i)) => {
+            let mut i = *i as isize;
+            if i < 0 {
+                i += arr.len() as isize;
+            }
+            let len = arr.len();
+            match arr.get_mut(i as usize) {
+                Some(e) => set_element(e, rest, v),
+                None => Err(RuntimeErrorKind::IndexOutOfBounds { index: i, len }),
+            }
+        }
+        (N::Object(map), N::Str(k)) => {
+            set_element(map.entry(k.clone()).or_insert(N::Unit), rest, v)
+        }
+        (target @ (N::Array(_) | N::Object(_)), index) => Err(RuntimeErrorKind::InvalidIndex {
+            target: target.type_name(),
+            index: index.type_name(),
+        }),
+        (var, _) => Err(RuntimeErrorKind::NotCallable(var.type_name())),
+    }
+}
+
+/// Value of a variable, read through its cell when a closure captured it
+fn load(var: &N) -> N {
+    match var {
+        N::Cell(cell) => cell.borrow().clone(),
+        var => var.clone(),
+    }
+}
+
+/// Store `v` in a variable, through its cell when a closure captured it, returns the old value
+fn store(var: &mut N, v: N) -> N {
+    match var {
+        N::Cell(cell) => cell.replace(v),
+        var => core::mem::replace(var, v),
+    }
+}
+
+/// Share a variable with a closure being created, see `N::Cell`
+fn capture(var: &mut N, by_value: bool) -> N {
+    match var {
+        _ if by_value => load(var),
+        N::Cell(cell) => N::Cell(cell.clone()),
+        var => {
+            let cell = Rc::new(RefCell::new(core::mem::replace(var, N::Unit)));
+            *var = N::Cell(cell.clone());
+            N::Cell(cell)
+        }
+    }
+}
+
+/// Give a new value to the variable `name` declared in the scope, or any parent scope
+fn assign(name: &str, v: N, span: Span, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    match ctx.find_var(name) {
+        Some((_, val)) => {
+            let size = v.heap_size();
+            let old = store(val, v);
+            ctx.mem = (ctx.mem + size).saturating_sub(old.heap_size());
+            Ok(N::Unit)
+        }
+        None => ctx.fail(
+            RuntimeErrorKind::UndefinedVariable(String::from(name)),
+            span,
+        ),
+    }
+}
+
+/// Give a new value to the variable at `slot` of the function frame
+fn assign_local(slot: usize, v: N, ctx: &mut Ctx) -> N {
+    let size = v.heap_size();
+    if let Some(val) = ctx.local(slot) {
+        let old = store(val, v);
+        ctx.mem = (ctx.mem + size).saturating_sub(old.heap_size());
+    }
+    N::Unit
+}
+
+/// Give a new value to a variable captured by the closure being evaluated
+fn assign_cell(cell: &RefCell<N>, v: N, ctx: &mut Ctx) -> N {
+    let size = v.heap_size();
+    let old = cell.replace(v);
+    ctx.mem = (ctx.mem + size).saturating_sub(old.heap_size());
+    N::Unit
+}
+
+/// Result of a logical operator decided by its left side alone, the right side is then not evaluated
+///
+/// `&` and `|` on an array are a filter and a reduce, they always need the right side.
+fn short_circuit(op: Op, lt: &N) -> Option<N> {
+    let decided = match op {
+        Op::And | Op::Or if matches!(lt, N::Array(_)) => return None,
+        Op::And | Op::And2 => !lt.to_bool(),
+        Op::Or | Op::Or2 => lt.to_bool(),
+        _ => return None,
+    };
+    decided.then(|| bool_n(lt.to_bool()))
+}
+
+fn unary(op: Op, val: N, span: Span, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    match (op, &val) {
+        (Op::Minus, N::Num(x)) => Ok(N::Num(-x)),
+        (Op::Plus, N::Num(x)) => Ok(N::Num(*x)),
+        (Op::Not, v) => Ok(bool_n(!v.to_bool())),
+        (Op::BitNot, N::Num(x)) if is_int(*x) => Ok(N::Num(!(*x as i64) as f64)),
+        _ => ctx.fail(
+            RuntimeErrorKind::InvalidOperand {
+                op,
+                operand: val.type_name(),
+            },
+            span,
+        ),
+    }
+}
+
+/// Apply the binary operator `op` to evaluated operands
+fn binary(op: Op, lt: N, rt: N, span: Span, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    if let (Op::Plus | Op::Plus2, Some(_)) = (op, ctx.max_mem) {
+        ctx.reserve(lt.heap_size() + rt.heap_size(), span)?;
+    }
+    let res = match (op, &lt, &rt) {
+        (Op::Plus, N::Num(li), N::Num(ri)) => N::Num(li + ri),
+        (Op::Plus, N::Num(li), N::Unit) => N::Num(*li),
+        (Op::Plus, N::Unit, N::Num(ri)) => N::Num(*ri),
+        (Op::Greater, N::Num(li), N::Num(ri)) => bool_n(li > ri),
+        (Op::Lesser, N::Num(li), N::Num(ri)) => bool_n(li < ri),
+        (Op::LesserEq, N::Num(li), N::Num(ri)) => bool_n(li <= ri),
+        (Op::GreaterEq, N::Num(li), N::Num(ri)) => bool_n(li >= ri),
+        (Op::Lesser | Op::Greater | Op::LesserEq | Op::GreaterEq, li, ri)
+            if compare(li, ri).is_some() =>
+        {
+            let ord = compare(li, ri).unwrap_or(Ordering::Equal);
+            bool_n(match op {
+                Op::Lesser => ord.is_lt(),
+                Op::Greater => ord.is_gt(),
+                Op::LesserEq => ord.is_le(),
+                _ => ord.is_ge(),
+            })
+        }
+        (Op::Equals, N::Num(li), N::Num(ri)) => bool_n(li == ri),
+        (Op::NotEquals, N::Num(li), N::Num(ri)) => bool_n(li != ri),
+        // Deep equality, values of different types are never equal, like in arrays
+        (Op::Equals, _, _) => bool_n(lt == rt),
+        (Op::NotEquals, _, _) => bool_n(lt != rt),
+        (Op::Minus, N::Num(li), N::Num(ri)) => N::Num(li - ri),
+        (Op::Mul, N::Num(li), N::Num(ri)) => N::Num(li * ri),
+        (Op::Div, N::Num(li), N::Num(ri)) => N::Num(li / ri),
+        (Op::Modulus, N::Num(li), N::Num(ri)) => N::Num(li.rem(ri)),
+        (Op::Range | Op::RangeInclusive, N::Num(li), N::Num(ri)) => N::Range {
+            start: *li,
+            end: *ri,
+            step: 1.0,
+            inclusive: op == Op::RangeInclusive,
+        },
+        (
+            Op::Step,
+            N::Range {
+                start,
+                end,
+                inclusive,
+                ..
+            },
+            N::Num(ri),
+        ) => N::Range {
+            start: *start,
+            // Without an end, counts down forever
+            end: match *end == f64::INFINITY && *ri < 0.0 {
+                true => f64::NEG_INFINITY,
+                false => *end,
+            },
+            step: *ri,
+            inclusive: *inclusive,
+        },
+        // Bitwise on 64-bit integers, the shift amount is taken modulo 64 (unlike js, 32)
+        (Op::Shift | Op::ShiftRight | Op::Xor, N::Num(li), N::Num(ri))
+            if is_int(*li) && is_int(*ri) =>
+        {
+            let (li, ri) = (*li as i64, *ri as i64);
+            N::Num(match op {
+                Op::Shift => li.wrapping_shl(ri as u32),
+                Op::ShiftRight => li.wrapping_shr(ri as u32),
+                _ => li ^ ri,
+            } as f64)
+        }
+        (Op::Shift, N::Array(li), ri) => {
+            N::Array(li.iter().chain(core::iter::once(ri)).cloned().collect())
+        }
+        (Op::Plus, N::Str(li), ri) => N::Str(format!("{}{}", li, ri.to_str())),
+        (Op::Plus, li, N::Str(ri)) => N::Str(format!("{}{}", li.to_str(), ri)),
+        (Op::Plus2, N::Array(li), N::Array(ri)) => N::Array(li.iter().chain(ri).cloned().collect()),
+        (Op::Plus2, N::Object(li), N::Object(ri)) => N::Object(
+            li.iter()
+                .chain(ri)
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+        ),
+        (Op::Plus, N::Object(li), N::Array(entry))
+            if matches!(entry.as_slice(), [N::Str(_), _]) =>
+        {
+            let mut obj = li.clone();
+            if let [N::Str(k), v] = entry.as_slice() {
+                obj.insert(k.clone(), v.clone());
+            }
+            N::Object(obj)
+        }
+        (Op::Plus, N::Array(li), ri) => {
+            N::Array(li.iter().chain(core::iter::once(ri)).cloned().collect())
+        }
+        (Op::Plus, li, N::Array(ri)) => {
+            N::Array(core::iter::once(li).chain(ri.iter()).cloned().collect())
+        }
+        (Op::And, N::Array(li), f) if f.is_func() => {
+            let mut new_arr = Vec::new();
+            for (index, e) in li.iter().enumerate() {
+                let keep = call(f, alloc::vec![e.clone(), N::Num(index as f64)], span, ctx)?;
+                if keep.to_bool() {
+                    new_arr.push(e.clone());
+                }
+            }
+            N::Array(new_arr)
+        }
+        (Op::Or, N::Array(li), f) if f.is_func() => {
+            let mut acc = li.first().cloned().unwrap_or(N::Unit);
+
+            for e in li.iter().skip(1) {
+                acc = call(f, alloc::vec![acc, e.clone()], span, ctx)?;
+            }
+            acc
+        }
+        (Op::And | Op::And2, li, ri) => bool_n(li.to_bool() && ri.to_bool()),
+        (Op::Or | Op::Or2, li, ri) => bool_n(li.to_bool() || ri.to_bool()),
+        _ => {
+            info!("unknown bin  {:?} {:?} {:?}", lt, op, rt);
+            ctx.fail(
+                RuntimeErrorKind::TypeMismatch {
+                    op,
+                    lhs: lt.type_name(),
+                    rhs: rt.type_name(),
+                },
+                span,
+            )?
+        }
+    };
+    Ok(res)
+}
+
+/// Call `f` with already evaluated arguments
+///
+/// Arrays are callable too: get with a number, slice with a range, map with a function, length without argument.
+/// So are objects: get with a string, map with a function, keys without argument.
+/// Strings can be sliced with a range.
+fn call(f: &N, args: Vec<N>, span: Span, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    match f {
+        N::FuncNativeDef(native) => {
+            let mut args = args.into_iter();
+            let mut arg = || args.next().unwrap_or(N::Unit);
+            Ok(native.0(arg(), arg(), arg(), arg()))
+        }
+        N::FuncDef {
+            args_name, scope, ..
+        } => ctx.scope(|ctx| {
+            if ctx.deep >= ctx.max_deep {
+                return Err(RuntimeError {
+                    kind: RuntimeErrorKind::StackOverflow,
+                    span,
+                });
+            }
+            ctx.deep += 1;
+            let frame = core::mem::replace(&mut ctx.frame, ctx.values.len());
+            let loops = core::mem::replace(&mut ctx.loops, 0);
+            let mut args = args.into_iter();
+            for arg_name in args_name.iter() {
+                ctx.set_val(arg_name, args.next().unwrap_or(N::Unit));
+            }
+            let res = eval_node(scope, ctx);
+            ctx.frame = frame;
+            ctx.loops = loops;
+            ctx.deep -= 1;
+            function_result(res, ctx)
+        }),
+        N::Array(_) | N::Object(_) | N::Str(_) => call_collection(f, args, span, ctx),
+        f => ctx.fail(RuntimeErrorKind::NotCallable(f.type_name()), span),
+    }
+}
+
+/// Index, map or length of an array or an object, slice of a string, out of `call` to keep its stack frame small
+fn call_collection(f: &N, args: Vec<N>, span: Span, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    match f {
+        N::Array(v) => match args.into_iter().next() {
+            Some(N::Num(i)) => {
+                let i = from_end(i, v.len()) as isize;
+                match v.get(i as usize) {
+                    Some(e) => Ok(e.clone()),
+                    _ => ctx.fail(
+                        RuntimeErrorKind::IndexOutOfBounds {
+                            index: i,
+                            len: v.len(),
+                        },
+                        span,
+                    ),
                 }
-                (Op::And, li, ri) => bool_n(li.to_bool() && ri.to_bool()),
-                (Op::Or, li, ri) => bool_n(li.to_bool() || ri.to_bool()),
-                _ => {
-                    info!("unknown bin  {:?} {:?} {:?}", lt, op, rt);
-                    N::Unit
+            }
+            Some(range @ N::Range { .. }) => match slice(&range, v.len()) {
+                Some(indexes) => {
+                    let size = indexes
+                        .iter()
+                        .map(|&i| core::mem::size_of::<N>() + v[i].heap_size())
+                        .sum();
+                    ctx.reserve(size, span)?;
+                    Ok(N::Array(
+                        indexes.into_iter().map(|i| v[i].clone()).collect(),
+                    ))
+                }
+                None => ctx.fail(
+                    RuntimeErrorKind::InvalidIndex {
+                        target: "array",
+                        index: FRACTIONAL_STEP,
+                    },
+                    span,
+                ),
+            },
+            Some(f) if f.is_func() => {
+                let arr = N::Array(
+                    v.iter()
+                        .enumerate()
+                        .map(|(index, e)| {
+                            call(&f, alloc::vec![e.clone(), N::Num(index as f64)], span, ctx)
+                        })
+                        .collect::<Result<_, _>>()?,
+                );
+                ctx.reserve(arr.heap_size(), span)?;
+                Ok(arr)
+            }
+            Some(index) => ctx.fail(
+                RuntimeErrorKind::InvalidIndex {
+                    target: "array",
+                    index: index.type_name(),
+                },
+                span,
+            ),
+            None => Ok(N::Num(v.len() as f64)),
+        },
+        N::Object(map) => match args.into_iter().next() {
+            Some(N::Str(key)) => Ok(map.get(&key).cloned().unwrap_or(N::Unit)),
+            Some(f) if f.is_func() => {
+                let mut res = BTreeMap::new();
+                for (key, e) in map.iter() {
+                    let args = alloc::vec![e.clone(), N::Str(key.clone())];
+                    res.insert(key.clone(), call(&f, args, span, ctx)?);
+                }
+                let obj = N::Object(res);
+                ctx.reserve(obj.heap_size(), span)?;
+                Ok(obj)
+            }
+            Some(index) => ctx.fail(
+                RuntimeErrorKind::InvalidIndex {
+                    target: "object",
+                    index: index.type_name(),
+                },
+                span,
+            ),
+            None => Ok(N::Array(map.keys().cloned().map(N::Str).collect())),
+        },
+        N::Str(s) => match args.into_iter().next() {
+            Some(range @ N::Range { .. }) => {
+                let chars: Vec<char> = s.chars().collect();
+                match slice(&range, chars.len()) {
+                    Some(indexes) => {
+                        let size = indexes.iter().map(|&i| chars[i].len_utf8()).sum();
+                        ctx.reserve(size, span)?;
+                        Ok(N::Str(indexes.into_iter().map(|i| chars[i]).collect()))
+                    }
+                    None => ctx.fail(
+                        RuntimeErrorKind::InvalidIndex {
+                            target: "string",
+                            index: FRACTIONAL_STEP,
+                        },
+                        span,
+                    ),
                 }
             }
+            index => ctx.fail(
+                RuntimeErrorKind::InvalidIndex {
+                    target: "string",
+                    index: index.map_or("unit", |i| i.type_name()),
+                },
+                span,
+            ),
+        },
+        f => ctx.fail(RuntimeErrorKind::NotCallable(f.type_name()), span),
+    }
+}
+
+/// Create a new FuncDef by replacing known variables (excluding shadowed)
+/// with cells shared with the frame being evaluated, or their value when `by_value`
+///
+/// `depth` is the number of functions between `n` and the frame being evaluated.
+pub fn dup(excl: &mut Vec<ID>, n: &N, depth: usize, by_value: bool, ctx: &mut Ctx) -> N {
+    info!("instanciate {:?}", n);
+    match n {
+        N::Block(scope) => N::Block(
+            scope
+                .iter()
+                .map(|e| dup(excl, e, depth, by_value, ctx))
+                .collect(),
+        ),
+        N::While { condition, body } => N::While {
+            condition: bx!(dup(excl, condition, depth, by_value, ctx)),
+            body: bx!(dup(excl, body, depth, by_value, ctx)),
+        },
+        N::For { vars, iter, body } => {
+            let iter = bx!(dup(excl, iter, depth, by_value, ctx));
+            // The loop variables shadow the captured ones in the body
+            let len = excl.len();
+            excl.extend(vars.iter().cloned());
+            let body = bx!(dup(excl, body, depth, by_value, ctx));
+            excl.truncate(len);
+            N::For {
+                vars: vars.clone(),
+                iter,
+                body,
+            }
         }
-        N::FuncDef { args_name, scope } => N::FuncDef {
+        N::Match { value, arms } => N::Match {
+            value: bx!(dup(excl, value, depth, by_value, ctx)),
+            arms: arms
+                .iter()
+                .map(|arm| {
+                    // The bound variables shadow the captured ones
+                    let len = excl.len();
+                    arm.pattern.names(excl);
+                    let guard = arm
+                        .guard
+                        .as_ref()
+                        .map(|guard| dup(excl, guard, depth, by_value, ctx));
+                    let body = dup(excl, &arm.body, depth, by_value, ctx);
+                    excl.truncate(len);
+                    Arm {
+                        pattern: arm.pattern.clone(),
+                        guard,
+                        body,
+                    }
+                })
+                .collect(),
+        },
+        N::FuncCall { func, args, span } => N::FuncCall {
+            func: bx!(dup(excl, func, depth, by_value, ctx)),
+            args: args
+                .iter()
+                .map(|e| dup(excl, e, depth, by_value, ctx))
+                .collect(),
+            span: *span,
+        },
+        N::FuncDef {
+            args_name,
+            scope,
+            by_value: inner_by_value,
+        } => N::FuncDef {
             args_name: args_name.clone(),
-            scope: bx!(dup(&mut args_name.clone(), &mut scope.clone(), ctx)),
+            scope: Rc::new(dup(excl, scope, depth + 1, by_value, ctx)),
+            by_value: *inner_by_value,
         },
-        N::Array(v) => N::Array(v.iter().map(|e| eval(e, ctx)).collect()),
-        e => {
-            info!("noop");
-            e.clone()
+        N::If {
+            condition,
+            path_true,
+            path_false,
+        } => N::If {
+            condition: bx!(dup(excl, condition, depth, by_value, ctx)),
+            path_true: bx!(dup(excl, path_true, depth, by_value, ctx)),
+            path_false: bx!(dup(excl, path_false, depth, by_value, ctx)),
+        },
+        N::Get(name, span) => {
+            if excl.contains(name) {
+                return N::Get(name.clone(), *span);
+            }
+            match ctx.find_var(name) {
+                Some((_, n)) => capture(n, by_value),
+                _ => N::Get(name.clone(), *span),
+            }
+        }
+        N::Local { depth: d, slot } if *d == depth => {
+            ctx.local(*slot).map_or(N::Unit, |v| capture(v, by_value))
+        }
+        N::Cell(cell) if by_value => cell.borrow().clone(),
+        N::Set(name, val) => {
+            let val = dup(excl, val, depth, by_value, ctx);
+            if let Some(index) = excl.iter().position(|x| x == name) {
+                excl.remove(index);
+            }
+            N::Set(name.clone(), bx!(val))
+        }
+        N::Destructure(pattern, val, span) => {
+            let val = dup(excl, val, depth, by_value, ctx);
+            let mut names = Vec::new();
+            pattern.names(&mut names);
+            excl.retain(|x| !names.contains(x));
+            N::Destructure(pattern.clone(), bx!(val), *span)
+        }
+        N::Binary(op, l, r, span) => N::Binary(
+            *op,
+            bx!(dup(excl, l, depth, by_value, ctx)),
+            bx!(dup(excl, r, depth, by_value, ctx)),
+            *span,
+        ),
+        N::Unary(op, val, span) => N::Unary(*op, bx!(dup(excl, val, depth, by_value, ctx)), *span),
+        N::Throw(val, span) => N::Throw(bx!(dup(excl, val, depth, by_value, ctx)), *span),
+        N::Return(val, span) => N::Return(bx!(dup(excl, val, depth, by_value, ctx)), *span),
+        N::Break(val, span) => N::Break(bx!(dup(excl, val, depth, by_value, ctx)), *span),
+        N::Try { body, catch } => N::Try {
+            body: bx!(dup(excl, body, depth, by_value, ctx)),
+            catch: bx!(dup(excl, catch, depth, by_value, ctx)),
+        },
+        N::Array(v) => N::Array(
+            v.iter()
+                .map(|e| dup(excl, e, depth, by_value, ctx))
+                .collect(),
+        ),
+        N::Concat(v, span) => N::Concat(
+            v.iter()
+                .map(|e| dup(excl, e, depth, by_value, ctx))
+                .collect(),
+            *span,
+        ),
+        N::Object(map) => N::Object(
+            map.iter()
+                .map(|(k, e)| (k.clone(), dup(excl, e, depth, by_value, ctx)))
+                .collect(),
+        ),
+        e => e.clone(),
+    }
+}
+
+/// Variables of a function, known at parse time
+struct Frame {
+    /// Declared variables, with their slot when it doesn't depend on the runtime
+    vars: Vec<(ID, Option<usize>)>,
+    /// Number of variables declared so far, `None` once it depends on the runtime
+    len: Option<usize>,
+}
+
+impl Frame {
+    fn new(args_name: &[ID]) -> Frame {
+        Frame {
+            vars: args_name
+                .iter()
+                .enumerate()
+                .map(|(slot, name)| (name.clone(), Some(slot)))
+                .collect(),
+            len: Some(args_name.len()),
+        }
+    }
+
+    fn declare(&mut self, name: &str, stmt: bool) {
+        let slot = self.len.filter(|_| stmt);
+        self.len = slot.map(|slot| slot + 1);
+        self.vars.push((String::from(name), slot));
+    }
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Frame::new(&[])
+    }
+}
+
+/// Turn the variables declared in the same function, or captured from a parent function, into `N::Local`
+///
+/// `stmt` is true when `n` is evaluated exactly once, in its block order.
+/// Variables declared elsewhere (in a condition, a loop without braces...) and
+/// the globals inserted by the host are left to the name lookup.
+/// Move the spans of `n` by `by` chars, the parser counts from the start of the code it was given
+fn offset_spans(n: &mut N, by: usize) {
+    if by == 0 {
+        return;
+    }
+    let offset = |span: &mut Span| {
+        span.start += by;
+        span.end += by;
+    };
+    match n {
+        N::Get(_, span) | N::Continue(span) => offset(span),
+        N::Throw(val, span)
+        | N::Return(val, span)
+        | N::Break(val, span)
+        | N::Unary(_, val, span)
+        | N::Destructure(_, val, span) => {
+            offset(span);
+            offset_spans(val, by);
+        }
+        N::Binary(_, l, r, span) => {
+            offset(span);
+            offset_spans(l, by);
+            offset_spans(r, by);
+        }
+        N::FuncCall { func, args, span } => {
+            offset(span);
+            offset_spans(func, by);
+            args.iter_mut().for_each(|a| offset_spans(a, by));
+        }
+        N::Concat(parts, span) => {
+            offset(span);
+            parts.iter_mut().for_each(|p| offset_spans(p, by));
+        }
+        N::Block(arr) | N::Array(arr) => arr.iter_mut().for_each(|e| offset_spans(e, by)),
+        N::Object(map) => map.values_mut().for_each(|e| offset_spans(e, by)),
+        N::Set(_, val) => offset_spans(val, by),
+        N::If {
+            condition,
+            path_true,
+            path_false,
+        } => {
+            offset_spans(condition, by);
+            offset_spans(path_true, by);
+            offset_spans(path_false, by);
+        }
+        N::While { condition, body } => {
+            offset_spans(condition, by);
+            offset_spans(body, by);
+        }
+        N::For { iter, body, .. } => {
+            offset_spans(iter, by);
+            offset_spans(body, by);
+        }
+        N::Match { value, arms } => {
+            offset_spans(value, by);
+            for arm in arms.iter_mut() {
+                if let Some(guard) = &mut arm.guard {
+                    offset_spans(guard, by);
+                }
+                offset_spans(&mut arm.body, by);
+            }
+        }
+        N::Try { body, catch } => {
+            offset_spans(body, by);
+            offset_spans(catch, by);
+        }
+        N::FuncDef { scope, .. } => offset_spans(Rc::make_mut(scope), by),
+        _ => {}
+    }
+}
+
+fn resolve(n: &mut N, frames: &mut Vec<Frame>, stmt: bool) {
+    match n {
+        N::Get(name, _) => {
+            let found = frames.iter().rev().enumerate().find_map(|(depth, frame)| {
+                let var = frame.vars.iter().rev().find(|(id, _)| id == name)?;
+                Some((depth, var.1))
+            });
+            if let Some((depth, Some(slot))) = found {
+                *n = N::Local { depth, slot };
+            }
+        }
+        N::Set(name, val) => {
+            resolve(val, frames, false);
+            if let Some(frame) = frames.last_mut() {
+                frame.declare(name, stmt);
+            }
+        }
+        N::Destructure(pattern, val, _) => {
+            resolve(val, frames, false);
+            let mut names = Vec::new();
+            pattern.names(&mut names);
+            if let Some(frame) = frames.last_mut() {
+                for name in names {
+                    frame.declare(&name, stmt);
+                }
+            }
+        }
+        N::Block(arr) => {
+            let Some(frame) = frames.last() else { return };
+            let (vars, len) = (frame.vars.len(), frame.len);
+            for e in arr.iter_mut() {
+                resolve(e, frames, true);
+            }
+            if let Some(frame) = frames.last_mut() {
+                frame.vars.truncate(vars);
+                frame.len = len;
+            }
+        }
+        N::FuncDef {
+            args_name, scope, ..
+        } => {
+            frames.push(Frame::new(args_name));
+            resolve(Rc::make_mut(scope), frames, true);
+            frames.pop();
+        }
+        N::While { condition, body } => {
+            // Declared at each iteration, visible from the start of the next one
+            let mut names = Vec::new();
+            declared(condition, &mut names);
+            declared(body, &mut names);
+            if let Some(frame) = frames.last_mut() {
+                for name in names {
+                    frame.declare(&name, false);
+                }
+            }
+            resolve(condition, frames, false);
+            resolve(body, frames, false);
+        }
+        N::For { vars, iter, body } => {
+            resolve(iter, frames, false);
+            // Each turn declares the loop variables in a new scope, like a block
+            let Some(frame) = frames.last_mut() else {
+                return;
+            };
+            let (len_vars, len) = (frame.vars.len(), frame.len);
+            for name in vars.iter() {
+                frame.declare(name, true);
+            }
+            resolve(body, frames, true);
+            if let Some(frame) = frames.last_mut() {
+                frame.vars.truncate(len_vars);
+                frame.len = len;
+            }
+        }
+        N::Match { value, arms } => {
+            resolve(value, frames, false);
+            for arm in arms.iter_mut() {
+                // Each arm declares its variables in a new scope, like a block
+                let Some(frame) = frames.last_mut() else {
+                    return;
+                };
+                let (len_vars, len) = (frame.vars.len(), frame.len);
+                let mut names = Vec::new();
+                arm.pattern.names(&mut names);
+                for name in names {
+                    frame.declare(&name, true);
+                }
+                if let Some(guard) = &mut arm.guard {
+                    resolve(guard, frames, false);
+                }
+                resolve(&mut arm.body, frames, true);
+                if let Some(frame) = frames.last_mut() {
+                    frame.vars.truncate(len_vars);
+                    frame.len = len;
+                }
+            }
+        }
+        N::If {
+            condition,
+            path_true,
+            path_false,
+        } => {
+            resolve(condition, frames, false);
+            resolve(path_true, frames, false);
+            resolve(path_false, frames, false);
+        }
+        N::FuncCall { func, args, .. } => {
+            resolve(func, frames, false);
+            for arg in args.iter_mut() {
+                resolve(arg, frames, false);
+            }
+        }
+        N::Binary(Op::Assign, l, r, _) => {
+            resolve(r, frames, false);
+            resolve(l, frames, false);
+        }
+        N::Binary(_, l, r, _) => {
+            resolve(l, frames, false);
+            resolve(r, frames, false);
+        }
+        N::Unary(_, val, _) | N::Throw(val, _) | N::Return(val, _) | N::Break(val, _) => {
+            resolve(val, frames, false)
+        }
+        N::Try { body, catch } => {
+            resolve(body, frames, false);
+            resolve(catch, frames, false);
         }
-    };
-
-    ctx.deep -= 1;
-    res
+        N::Array(v) | N::Concat(v, _) => {
+            for e in v.iter_mut() {
+                resolve(e, frames, false);
+            }
+        }
+        N::Object(map) => {
+            for e in map.values_mut() {
+                resolve(e, frames, false);
+            }
+        }
+        _ => {}
+    }
 }
 
-/// Create a new FuncDef by replacing known variables (excluding shadowed)
-pub fn dup(excl: &mut Vec<ID>, n: &mut N, ctx: &mut Ctx) -> N {
-    info!("instanciate {:?}", n);
+/// Names of the variables declared by `n`, outside of its blocks and functions
+fn declared(n: &N, names: &mut Vec<ID>) {
     match n {
-        N::Block(scope) => N::Block(scope.iter_mut().map(|e| dup(excl, e, ctx)).collect()),
-        N::While { condition, body } => N::While {
-            condition: bx!(dup(excl, condition, ctx)),
-            body: bx!(dup(excl, body, ctx)),
-        },
-        N::FuncCall { func, args } => N::FuncCall {
-            func: bx!(dup(excl, func, ctx)),
-            args: args.iter_mut().map(|e| dup(excl, e, ctx)).collect(),
-        },
-        N::FuncDef { args_name, scope } => N::FuncDef {
-            args_name: args_name.clone(),
-            scope: bx!(dup(excl, scope, ctx)),
-        },
+        N::Set(name, val) => {
+            declared(val, names);
+            names.push(name.clone());
+        }
+        N::Destructure(pattern, val, _) => {
+            declared(val, names);
+            pattern.names(names);
+        }
+        N::While { condition, body } => {
+            declared(condition, names);
+            declared(body, names);
+        }
+        N::For { iter, .. } => declared(iter, names),
+        N::Match { value, .. } => declared(value, names),
         N::If {
             condition,
             path_true,
             path_false,
-        } => N::If {
-            condition: bx!(dup(excl, condition, ctx)),
-            path_true: bx!(dup(excl, path_true, ctx)),
-            path_false: bx!(dup(excl, path_false, ctx)),
-        },
-        N::Get(name) => {
-            if excl.contains(name) {
-                return N::Get(name.clone());
+        } => {
+            declared(condition, names);
+            declared(path_true, names);
+            declared(path_false, names);
+        }
+        N::FuncCall { func, args, .. } => {
+            declared(func, names);
+            args.iter().for_each(|e| declared(e, names));
+        }
+        N::Binary(_, l, r, _) => {
+            declared(l, names);
+            declared(r, names);
+        }
+        N::Unary(_, val, _) | N::Throw(val, _) | N::Return(val, _) | N::Break(val, _) => {
+            declared(val, names)
+        }
+        N::Try { body, catch } => {
+            declared(body, names);
+            declared(catch, names);
+        }
+        N::Array(v) | N::Concat(v, _) => v.iter().for_each(|e| declared(e, names)),
+        N::Object(map) => map.values().for_each(|e| declared(e, names)),
+        _ => {}
+    }
+}
+
+/// Skip the whitespaces and comments, an unterminated comment is reported by [next_token]
+fn skip_whitespaces(i: &mut usize, code: &[char]) {
+    let _ = skip_trivia(i, code, &mut |_| {});
+}
+
+/// Skip the whitespaces and comments, `comment` is called with the span of each comment
+///
+/// `// line` comments end with the line, `/* block */` comments can be nested.
+/// An unterminated block comment runs to the end of the code and is an error, `i` is left
+/// at its start: more input may complete it (REPL).
+fn skip_trivia(
+    i: &mut usize,
+    code: &[char],
+    comment: &mut impl FnMut(Span),
+) -> Result<(), ParseError> {
+    let at = |i: usize, s: [char; 2]| code.get(i..i + 2) == Some(&s[..]);
+    while *i < code.len() {
+        let start = *i;
+        if code[*i] == ' ' || code[*i] == '\n' {
+            *i += 1;
+            continue;
+        } else if at(*i, ['/', '/']) {
+            while *i < code.len() && code[*i] != '\n' {
+                *i += 1;
             }
-            match ctx.find_var(name) {
-                Some((_, n)) => n.clone(),
-                _ => N::Get(name.clone()),
+        } else if at(*i, ['/', '*']) {
+            let mut depth = 0;
+            while *i < code.len() {
+                if at(*i, ['/', '*']) {
+                    depth += 1;
+                    *i += 2;
+                } else if at(*i, ['*', '/']) {
+                    depth -= 1;
+                    *i += 2;
+                    if depth == 0 {
+                        break;
+                    }
+                } else {
+                    *i += 1;
+                }
             }
-        }
-        N::Set(name, val) => {
-            if let Some(index) = excl.iter().position(|x| x == name) {
-                excl.remove(index);
+            if depth > 0 {
+                comment(Span { start, end: *i });
+                *i = start;
+                return Err(ParseError {
+                    pos: start,
+                    line: 0,
+                    col: 0,
+                    expected: "`*/`",
+                    found: None,
+                    msg: "unterminated comment",
+                });
             }
-            N::Set(name.clone(), val.clone())
+        } else {
+            break;
         }
-        N::Binary(op, l, r) => N::Binary(*op, bx!(dup(excl, l, ctx)), bx!(dup(excl, r, ctx))),
-        N::Array(v) => N::Array(v.iter_mut().map(|e| dup(excl, e, ctx)).collect()),
-        e => e.clone(),
+        comment(Span { start, end: *i });
     }
+    Ok(())
 }
 
-fn next_token(i: &mut usize, code: &[char]) -> Token {
-    let skip_whitespaces = |i: &mut usize| {
-        while *i < code.len() && (code[*i] == ' ' || code[*i] == '\n') {
-            *i += 1;
+/// Spans of the comments in `code`, in order. Comments are skipped by the parser,
+/// tools rewriting the code (formatters...) can put them back from there.
+pub fn comments(code: &str) -> Vec<Span> {
+    let code: Vec<char> = code.chars().collect();
+    let mut res = Vec::new();
+    let mut i = 0;
+    while i < code.len() {
+        if skip_trivia(&mut i, &code, &mut |span| res.push(span)).is_err() {
+            break;
         }
-    };
+        let start = i;
+        next_token(&mut i, &code);
+        i = i.max(start + 1);
+    }
+    res
+}
+
+/// `true`, `false`, `unit` and `null` are values, not variable names
+fn is_keyword_literal(id: &str) -> bool {
+    matches!(id, "true" | "false" | "unit" | "null")
+}
 
+fn next_token(i: &mut usize, code: &[char]) -> Token {
     let parse_number = |i: &mut usize| {
         let backup_i = *i;
         let mut id = String::from("");
-        while code.len() > *i && (code[*i].is_ascii_digit() || code[*i] == '.') {
+        // `..` after a number is a range
+        while code.len() > *i
+            && (code[*i].is_ascii_digit() || (code[*i] == '.' && code.get(*i + 1) != Some(&'.')))
+        {
             id = format!("{}{}", id, code[*i]);
             *i += 1;
         }
@@ -513,35 +2468,46 @@ fn next_token(i: &mut usize, code: &[char]) -> Token {
         true
     };
     loop {
-        skip_whitespaces(i);
+        if let Err(e) = skip_trivia(i, code, &mut |_| {}) {
+            break Token::Err(e);
+        }
 
         if *i >= code.len() {
-            break Token::Err(String::from("i>code"));
+            break Token::Err(ParseError::unexpected(
+                code,
+                *i,
+                "token",
+                "unexpected end of input",
+            ));
         }
 
-        if code[*i] == '"' {
-            let mut builder = String::from("");
-            while *i + 1 < code.len() {
-                *i += 1;
-                match code[*i] {
-                    '"' => {
-                        *i += 1;
-                        return Token::Quoted(builder);
-                    }
-                    c => builder.push(c),
-                }
-            }
-            return Token::Err(String::from("i>code"));
+        if code[*i] == '"' || (code[*i] == 'r' && matches!(code.get(*i + 1), Some('"' | '#'))) {
+            return lex_string(i, code);
+        }
+
+        if code[*i] == '`' {
+            return lex_template(i, code);
         }
 
         for (s, tok) in [
             ("if", Token::If),
             ("else", Token::Else),
             ("while", Token::While),
+            ("throw", Token::Throw),
+            ("try", Token::Try),
+            ("catch", Token::Catch),
+            ("move", Token::Move),
+            ("return", Token::Return),
+            ("break", Token::Break),
+            ("continue", Token::Continue),
+            ("for", Token::For),
+            ("in", Token::In),
+            ("match", Token::Match),
         ] {
             if starts_with(*i, s)
-                && *i + s.len() < code.len()
-                && [' ', '{'].contains(&code[*i + s.len()])
+                && !code
+                    .get(*i + s.len())
+                    .is_some_and(|c| c.is_alphanumeric() || *c == '_')
             {
                 *i += s.len();
                 return tok;
@@ -550,14 +2516,38 @@ fn next_token(i: &mut usize, code: &[char]) -> Token {
 
         if starts_with(*i, "let ") && *i + 4 < code.len() {
             *i += 4;
-            skip_whitespaces(i);
+            skip_whitespaces(i, code);
+            if matches!(code.get(*i), Some('[' | '{')) {
+                break Token::LetPattern;
+            }
+            let name_start = *i;
             let id = match parse_ident(i) {
+                Some(id) if is_keyword_literal(&id) => {
+                    break Token::Err(ParseError::unexpected(
+                        code,
+                        name_start,
+                        "identifier",
+                        "cannot bind a variable to a keyword literal",
+                    ))
+                }
                 Some(id) => id,
-                None => break Token::Err(String::from("no id after let # ")),
+                None => {
+                    break Token::Err(ParseError::unexpected(
+                        code,
+                        *i,
+                        "identifier",
+                        "missing variable name after `let`",
+                    ))
+                }
             };
-            skip_whitespaces(i);
+            skip_whitespaces(i, code);
             if *i >= code.len() || code[*i] != '=' {
-                break Token::Err(String::from("no equal after let 'id' # "));
+                break Token::Err(ParseError::unexpected(
+                    code,
+                    *i,
+                    "`=`",
+                    "missing `=` after `let` variable name",
+                ));
             }
             *i += 1;
             break Token::Let(id);
@@ -567,15 +2557,32 @@ fn next_token(i: &mut usize, code: &[char]) -> Token {
             break Token::N(N::Num(num));
         }
 
+        let start = *i;
         if let Some(id) = parse_ident(i) {
-            break Token::N(N::Get(id));
+            break Token::N(match id.as_str() {
+                "true" => N::Bool(true),
+                "false" => N::Bool(false),
+                "unit" | "null" => N::Unit,
+                _ => N::Get(id, Span { start, end: *i }),
+            });
+        }
+
+        if starts_with(*i, "..=") {
+            *i += 3;
+            return Token::Bin(Op::RangeInclusive);
         }
 
         for (st, tok) in [
+            ("..", Token::Bin(Op::Range)),
             ("==", Token::Bin(Op::Equals)),
             ("!=", Token::Bin(Op::NotEquals)),
             ("++", Token::Bin(Op::Plus2)),
+            ("&&", Token::Bin(Op::And2)),
+            ("||", Token::Bin(Op::Or2)),
             ("<<", Token::Bin(Op::Shift)),
+            (">>", Token::Bin(Op::ShiftRight)),
+            ("<=", Token::Bin(Op::LesserEq)),
+            (">=", Token::Bin(Op::GreaterEq)),
             ("=>", Token::Assoc),
         ] {
             if starts_with(*i, st) {
@@ -602,6 +2609,11 @@ fn next_token(i: &mut usize, code: &[char]) -> Token {
             ('|', Token::Bin(Op::Or)),
             ('[', Token::ArrayStart),
             (']', Token::ArrayEnd),
+            (':', Token::Colon),
+            ('.', Token::Dot),
+            ('!', Token::Not),
+            ('~', Token::Tilde),
+            ('^', Token::Bin(Op::Xor)),
         ] {
             if code[*i] == key {
                 *i += 1;
@@ -613,103 +2625,484 @@ fn next_token(i: &mut usize, code: &[char]) -> Token {
     }
 }
 
+/// Lex a quoted string starting at `i`, either `"..."` with escapes or raw `r#"..."#`
+///
+/// Raw strings end at a `"` followed by as many `#` as after the `r`, nothing is escaped inside.
+fn lex_string(i: &mut usize, code: &[char]) -> Token {
+    let start = *i;
+    let raw = code[*i] == 'r';
+    let mut hashes = 0;
+    if raw {
+        *i += 1;
+        while *i < code.len() && code[*i] == '#' {
+            hashes += 1;
+            *i += 1;
+        }
+        if *i >= code.len() || code[*i] != '"' {
+            return Token::Err(ParseError::unexpected(
+                code,
+                *i,
+                "`\"`",
+                "missing `\"` to start the raw string",
+            ));
+        }
+    }
+    let mut builder = String::from("");
+    *i += 1;
+    while *i < code.len() {
+        match code[*i] {
+            '"' if code[*i + 1..].iter().take_while(|c| **c == '#').count() >= hashes => {
+                *i += 1 + hashes;
+                return Token::Quoted(builder);
+            }
+            // A `\` ending the code is unterminated, more input may complete it (REPL)
+            '\\' if !raw && *i + 1 < code.len() => match escape(i, code) {
+                Ok(c) => builder.push(c),
+                Err(e) => return Token::Err(e),
+            },
+            c => {
+                builder.push(c);
+                *i += 1;
+            }
+        }
+    }
+    Token::Err(ParseError {
+        pos: start,
+        line: 0,
+        col: 0,
+        expected: "`\"`",
+        found: None,
+        msg: "unterminated string",
+    })
+}
+
+/// Lex a template string starting at `i`, the expressions inside `${}` are parsed
+///
+/// Without any expression, it is a plain quoted string.
+fn lex_template(i: &mut usize, code: &[char]) -> Token {
+    let start = *i;
+    let mut parts = Vec::new();
+    let mut builder = String::from("");
+    *i += 1;
+    while *i < code.len() {
+        match code[*i] {
+            '`' => {
+                *i += 1;
+                if parts.is_empty() {
+                    return Token::Quoted(builder);
+                }
+                if !builder.is_empty() {
+                    parts.push(N::Str(builder));
+                }
+                return Token::N(N::Concat(parts, Span { start, end: *i }));
+            }
+            '\\' if *i + 1 < code.len() => match escape(i, code) {
+                Ok(c) => builder.push(c),
+                Err(e) => return Token::Err(e),
+            },
+            '$' if code.get(*i + 1) == Some(&'{') => {
+                *i += 2;
+                if !builder.is_empty() {
+                    parts.push(N::Str(core::mem::take(&mut builder)));
+                }
+                match parse_expr(i, code, 0) {
+                    Ok(e) => parts.push(e),
+                    Err(e) => return Token::Err(e),
+                }
+                let k = *i;
+                if !matches!(next_token(i, code), Token::BlockEnd) {
+                    return Token::Err(ParseError::unexpected(
+                        code,
+                        k,
+                        "`}`",
+                        "unclosed template expression",
+                    ));
+                }
+            }
+            c => {
+                builder.push(c);
+                *i += 1;
+            }
+        }
+    }
+    Token::Err(ParseError {
+        pos: start,
+        line: 0,
+        col: 0,
+        expected: "`` ` ``",
+        found: None,
+        msg: "unterminated template string",
+    })
+}
+
+/// Decode the escape sequence starting with the `\` at `i`, and move after it
+fn escape(i: &mut usize, code: &[char]) -> Result<char, ParseError> {
+    let start = *i;
+    let invalid = |end: usize, expected, msg| ParseError {
+        pos: start,
+        line: 0,
+        col: 0,
+        expected,
+        found: Some(code[start..end.min(code.len())].iter().collect()),
+        msg,
+    };
+    let c = match code.get(start + 1) {
+        Some('n') => '\n',
+        Some('t') => '\t',
+        Some('r') => '\r',
+        Some('0') => '\0',
+        Some('\\') => '\\',
+        Some('"') => '"',
+        Some('\'') => '\'',
+        Some('`') => '`',
+        Some('$') => '$',
+        Some('u') => {
+            let digits = code[(start + 2).min(code.len())..]
+                .iter()
+                .skip(1)
+                .take_while(|c| c.is_ascii_hexdigit())
+                .count();
+            let end = start + 3 + digits;
+            if code.get(start + 2) != Some(&'{') || code.get(end) != Some(&'}') {
+                return Err(invalid(end + 1, "`\\u{...}`", "invalid unicode escape"));
+            }
+            let hex: String = code[start + 3..end].iter().collect();
+            *i = end + 1;
+            return u32::from_str_radix(&hex, 16)
+                .ok()
+                .filter(|_| digits <= 6)
+                .and_then(char::from_u32)
+                .ok_or_else(|| invalid(end + 1, "unicode scalar value", "invalid unicode escape"));
+        }
+        _ => {
+            return Err(invalid(
+                start + 2,
+                "escape sequence",
+                "invalid escape in string",
+            ))
+        }
+    };
+    *i += 2;
+    Ok(c)
+}
+
 fn pa(i: usize) -> String {
     format!("{:width$}", "", width = i * 5)
 }
-type Error = &'static str;
 
-fn parse_expr(i: &mut usize, code: &[char], pad: usize) -> Result<N, Error> {
+/// Deepest recursion of the parser, 3 levels per nested block or call.
+///
+/// Deeper code is rejected instead of overflowing the native stack, each level costs
+/// up to 1KB in release builds, 4KB in debug.
+const MAX_PARSE_DEPTH: usize = 1024;
+const TOO_DEEP: &str = "expression nested too deeply";
+
+/// Fail past `MAX_PARSE_DEPTH`, the nodes would nest too deep to be parsed and evaluated
+fn check_depth(i: usize, code: &[char], pad: usize) -> Result<(), ParseError> {
+    match pad > MAX_PARSE_DEPTH {
+        true => Err(ParseError::unexpected(code, i, "expression", TOO_DEEP)),
+        false => Ok(()),
+    }
+}
+
+fn parse_expr(i: &mut usize, code: &[char], pad: usize) -> Result<N, ParseError> {
     info!(
         "{}parse expr {:?}",
         pa(pad),
         &code[*i..(*i + 5).min(if code.is_empty() { *i } else { code.len() - 1 })]
     );
-    let term = parse_term(i, code, pad + 1)?;
-
-    let mut j = *i;
-    let token = next_token(&mut j, code);
+    parse_binary(i, code, pad, 0)
+}
 
-    if let Token::Bin(op) = token {
-        // if op.term_separate()
-        {
-            *i = j;
-            let term_right = parse_expr(i, code, pad + 1)?;
-            let n = N::Binary(op, bx!(term), bx!(term_right));
-            return Ok(n);
+/// Precedence climbing: parse operands joined by operators binding at least as tight as `min`
+///
+/// Operators are left associative, except `=` which can't be chained (`a = b = 1`).
+fn parse_binary(i: &mut usize, code: &[char], mut pad: usize, min: u8) -> Result<N, ParseError> {
+    skip_whitespaces(i, code);
+    let start = *i;
+    let mut lhs = parse_postfix(i, code, pad + 1)?;
+    loop {
+        // Each operator nests the operands parsed so far one level deeper
+        pad += 1;
+        let mut j = *i;
+        let token = next_token(&mut j, code);
+        info!("{}{:?}", pa(pad), token);
+        let op = match token {
+            Token::Bin(op) if op.precedence() >= min => op,
+            // Not a keyword, `step` can still name a variable
+            Token::N(N::Get(name, _))
+                if name == "step"
+                    && matches!(lhs, N::Binary(Op::Range | Op::RangeInclusive, ..))
+                    && Op::Step.precedence() >= min =>
+            {
+                Op::Step
+            }
+            _ => return Ok(lhs),
+        };
+        if op == Op::Assign && matches!(lhs, N::Binary(Op::Assign, ..)) {
+            // An assignment evaluates to unit, `a` would be set to unit
+            return Err(ParseError::unexpected(
+                code,
+                *i,
+                "end of assignment",
+                "chained assignment",
+            ));
         }
+        *i = j;
+        let next_min = op.precedence() + 1;
+        let rhs = match op {
+            Op::Range | Op::RangeInclusive => range_end(i, code, pad, next_min),
+            _ => parse_binary(i, code, pad + 1, next_min)?,
+        };
+        lhs = N::Binary(op, bx!(lhs), bx!(rhs), Span { start, end: *i });
     }
-
-    Ok(term)
 }
 
-fn parse_term(i: &mut usize, code: &[char], pad: usize) -> Result<N, Error> {
-    info!(
-        "{}parse_term {:?}",
-        pa(pad),
-        &code[*i..(*i + 5).min(if code.is_empty() { *i } else { code.len() - 1 })]
-    );
-
-    let factor = parse_factor(i, code, pad + 1)?;
+/// End of a range, infinite when missing like in `s(-3..)` or `s(0.. step -1)`
+fn range_end(i: &mut usize, code: &[char], pad: usize, min: u8) -> N {
     let mut j = *i;
-    let token = next_token(&mut j, code);
-    info!("{:?}", token);
-    match token {
-        Token::Bin(Op::Mul) | Token::Bin(Op::Div) | Token::ParStart => {
+    if matches!(next_token(&mut j, code), Token::N(N::Get(name, _)) if name == "step") {
+        return N::Num(f64::INFINITY);
+    }
+    let mut j = *i;
+    match parse_binary(&mut j, code, pad + 1, min) {
+        Ok(end) => {
             *i = j;
-            match token {
-                Token::Bin(op) if !op.term_separate() => {
-                    let factor_right = parse_term(i, code, pad + 1)?;
-                    let n = N::Binary(op, bx!(factor), bx!(factor_right));
-                    return Ok(n);
+            end
+        }
+        Err(_) => N::Num(f64::INFINITY),
+    }
+}
+
+/// A factor followed by calls and field accesses, chained
+fn parse_postfix(i: &mut usize, code: &[char], mut pad: usize) -> Result<N, ParseError> {
+    skip_whitespaces(i, code);
+    let start = *i;
+    let mut factor = parse_factor(i, code, pad + 1)?;
+    loop {
+        pad += 1;
+        check_depth(*i, code, pad)?;
+        let mut j = *i;
+        let token = next_token(&mut j, code);
+        info!("{:?}", token);
+        match token {
+            Token::ParStart => {
+                info!("Function call start");
+                *i = j;
+                let mut args = Vec::new();
+                let arg_err = loop {
+                    info!("args enum");
+                    let mut j = *i;
+                    let e = parse_expr(&mut j, code, pad + 1);
+                    match e {
+                        Ok(expr) => {
+                            info!("args enum got");
+                            *i = j;
+                            args.push(expr);
+
+                            let mut k = *i;
+                            let token = next_token(&mut k, code);
+                            if let Token::Comma = token {
+                                *i = k
+                            }
+                        }
+                        Err(e) => {
+                            info!("args enum end");
+                            break e;
+                        }
+                    }
+                };
+                let k = *i;
+                let token = next_token(i, code);
+                if let Token::ParEnd = token {
+                    factor = N::FuncCall {
+                        func: bx!(factor),
+                        args,
+                        span: Span { start, end: *i },
+                    };
+                } else {
+                    return Err(
+                        ParseError::unexpected(code, k, "`)`", "unclosed function call")
+                            .furthest(arg_err),
+                    );
                 }
+            }
+            Token::Dot => {
+                *i = j;
+                let k = *i;
+                let Token::N(N::Get(name, _)) = next_token(i, code) else {
+                    return Err(ParseError::unexpected(
+                        code,
+                        k,
+                        "field name",
+                        "missing field name after `.`",
+                    ));
+                };
+                factor = N::FuncCall {
+                    func: bx!(factor),
+                    args: alloc::vec![N::Str(name)],
+                    span: Span { start, end: *i },
+                };
+            }
+            _ => return Ok(factor),
+        }
+    }
+}
 
-                Token::ParStart => {
-                    info!("Function call start");
-                    let mut args = Vec::new();
-                    loop {
-                        info!("args enum");
-                        let mut j = *i;
-                        let e = parse_expr(&mut j, code, pad + 1);
-                        match e {
-                            Ok(expr) => {
-                                info!("args enum got");
+/// Parse a `match` pattern
+fn parse_pattern(i: &mut usize, code: &[char]) -> Result<Pattern, ParseError> {
+    let k = *i;
+    let invalid = |expected, msg| Err(ParseError::unexpected(code, k, expected, msg));
+    match next_token(i, code) {
+        Token::N(N::Get(name, _)) if name == "_" => Ok(Pattern::Wildcard),
+        Token::N(N::Get(name, _)) => Ok(Pattern::Bind(name)),
+        Token::N(n @ (N::Num(_) | N::Bool(_) | N::Unit)) => Ok(Pattern::Value(n)),
+        Token::Quoted(s) => Ok(Pattern::Value(N::Str(s))),
+        Token::Bin(Op::Minus) => match next_token(i, code) {
+            Token::N(N::Num(x)) => Ok(Pattern::Value(N::Num(-x))),
+            _ => invalid("number", "invalid negative number pattern"),
+        },
+        Token::ArrayStart => {
+            let mut items = Vec::new();
+            loop {
+                let mut j = *i;
+                let item = match next_token(&mut j, code) {
+                    Token::ArrayEnd => {
+                        *i = j;
+                        return Ok(Pattern::Array(items));
+                    }
+                    Token::Comma => {
+                        *i = j;
+                        continue;
+                    }
+                    Token::Bin(Op::Range)
+                        if items.iter().any(|p| matches!(p, Pattern::Rest(_))) =>
+                    {
+                        return Err(ParseError::unexpected(
+                            code,
+                            *i,
+                            "pattern",
+                            "only one `..` rest per array pattern",
+                        ))
+                    }
+                    Token::Bin(Op::Range) => {
+                        *i = j;
+                        // The name is optional, `..` skips the elements
+                        match next_token(&mut j, code) {
+                            Token::N(N::Get(name, _)) => {
                                 *i = j;
-                                args.push(expr);
-
-                                let mut k = *i;
-                                let token = next_token(&mut k, code);
-                                if let Token::Comma = token {
-                                    *i = k
-                                }
-                            }
-                            Err(_) => {
-                                info!("args enum end");
-                                break;
+                                Pattern::Rest(Some(name))
                             }
+                            _ => Pattern::Rest(None),
                         }
                     }
-                    let token = next_token(i, code);
-                    if let Token::ParEnd = token {
-                        return Ok(N::FuncCall {
-                            func: bx!(factor),
-                            args,
-                        });
-                    } else {
-                        return Err("No parenthesis close");
+                    _ => parse_pattern(i, code)?,
+                };
+                items.push(item);
+            }
+        }
+        Token::BlockStart => {
+            let mut fields = Vec::new();
+            loop {
+                let k = *i;
+                let (key, quoted) = match next_token(i, code) {
+                    Token::BlockEnd => return Ok(Pattern::Object(fields)),
+                    Token::Comma => continue,
+                    Token::N(N::Get(key, _)) => (key, false),
+                    Token::Quoted(key) => (key, true),
+                    _ => {
+                        return Err(ParseError::unexpected(
+                            code,
+                            k,
+                            "field name",
+                            "invalid object pattern",
+                        ))
+                    }
+                };
+                let mut j = *i;
+                let p = match next_token(&mut j, code) {
+                    Token::Colon => {
+                        *i = j;
+                        parse_pattern(i, code)?
                     }
-                }
-                _ => {}
+                    // `{x}` binds the field `x`
+                    _ if !quoted => Pattern::Bind(key.clone()),
+                    _ => {
+                        return Err(ParseError::unexpected(
+                            code,
+                            *i,
+                            "`:`",
+                            "missing pattern after a quoted field name",
+                        ))
+                    }
+                };
+                fields.push((key, p));
             }
         }
-        _ => {}
+        Token::Err(e) => Err(e),
+        _ => invalid("pattern", "invalid pattern"),
     }
+}
+
+/// After a `{`, tell an object `{a: 1}` or `{:}` from a block
+fn is_object(mut i: usize, code: &[char]) -> bool {
+    match next_token(&mut i, code) {
+        Token::Colon => true,
+        Token::N(N::Get(..)) | Token::Quoted(_) => matches!(next_token(&mut i, code), Token::Colon),
+        _ => false,
+    }
+}
 
-    Ok(factor)
+/// Parse the fields of an object, after its `{`
+fn parse_object(i: &mut usize, code: &[char], pad: usize) -> Result<N, ParseError> {
+    let mut map = BTreeMap::new();
+    let mut j = *i;
+    if let Token::Colon = next_token(&mut j, code) {
+        *i = j;
+    }
+    loop {
+        let k = *i;
+        let key = match next_token(i, code) {
+            Token::N(N::Get(key, _)) | Token::Quoted(key) => key,
+            Token::BlockEnd => break,
+            _ => {
+                return Err(ParseError::unexpected(
+                    code,
+                    k,
+                    "field name",
+                    "invalid object field",
+                ))
+            }
+        };
+        let k = *i;
+        let Token::Colon = next_token(i, code) else {
+            return Err(ParseError::unexpected(
+                code,
+                k,
+                "`:`",
+                "missing `:` after field name",
+            ));
+        };
+        map.insert(key, parse_expr(i, code, pad + 1)?);
+        let k = *i;
+        match next_token(i, code) {
+            Token::Comma => {}
+            Token::BlockEnd => break,
+            _ => return Err(ParseError::unexpected(code, k, "`}`", "unclosed object")),
+        }
+    }
+    Ok(N::Object(map))
 }
 
-fn parse_factor(i: &mut usize, code: &[char], pad: usize) -> Result<N, Error> {
+fn parse_factor(i: &mut usize, code: &[char], pad: usize) -> Result<N, ParseError> {
+    check_depth(*i, code, pad)?;
     if *i >= code.len() {
-        return Err("EOF");
+        return Err(ParseError::unexpected(
+            code,
+            *i,
+            "expression",
+            "unexpected end of input",
+        ));
     }
 
     info!(
@@ -718,141 +3111,425 @@ fn parse_factor(i: &mut usize, code: &[char], pad: usize) -> Result<N, Error> {
         &code[*i..(*i + 5).min(if code.is_empty() { *i } else { code.len() - 1 })]
     );
 
+    skip_whitespaces(i, code);
+    let start = *i;
     let token = next_token(i, code);
     info!("{}{:?}", pa(pad), token);
-    if let Token::BlockStart = token {
-        let mut scope = Vec::new();
-
-        loop {
-            let mut j = *i;
-            let e = parse_expr(&mut j, code, pad + 1);
-            match e {
-                Ok(expr) => {
-                    *i = j;
-                    scope.push(expr);
-                }
-                Err(_) => {
-                    break;
-                }
+    let prefix = match token {
+        Token::Bin(op @ (Op::Minus | Op::Plus)) => Some(op),
+        Token::Not => Some(Op::Not),
+        Token::Tilde => Some(Op::BitNot),
+        _ => None,
+    };
+    if let Some(op) = prefix {
+        let val = parse_postfix(i, code, pad + 1)?;
+        return Ok(match (op, val) {
+            (Op::Minus, N::Num(x)) => N::Num(-x),
+            (Op::Plus, N::Num(x)) => N::Num(x),
+            (op, val) => N::Unary(op, bx!(val), Span { start, end: *i }),
+        });
+    }
+    // Range from 0, `..3`
+    if let Token::Bin(op @ (Op::Range | Op::RangeInclusive)) = token {
+        let end = range_end(i, code, pad, op.precedence() + 1);
+        return Ok(N::Binary(
+            op,
+            bx!(N::Num(0.0)),
+            bx!(end),
+            Span { start, end: *i },
+        ));
+    }
+
+    // Arms only dispatch, like `eval_match`, the parsers recurse through here
+    match token {
+        Token::BlockStart => parse_block(i, code, pad),
+        Token::ArrayStart => parse_array(i, code, pad),
+        Token::ParStart => parse_func(i, code, pad),
+        Token::Move => parse_move(i, code, pad),
+        Token::Quoted(s) => Ok(N::Str(s)),
+        Token::While => parse_while(i, code, pad),
+        Token::For => parse_for(i, code, pad),
+        Token::Match => parse_match(i, code, pad),
+        Token::If => parse_if(i, code, pad),
+        Token::Throw | Token::Return | Token::Break => parse_escape(i, code, pad, token, start),
+        Token::Continue => Ok(N::Continue(Span { start, end: *i })),
+        Token::Try => parse_try(i, code, pad),
+        Token::Let(name) => parse_let(i, code, pad, name),
+        Token::LetPattern => parse_let_pattern(i, code, pad, start),
+        Token::N(n @ (N::Num(_) | N::Get(..) | N::Concat(..) | N::Bool(_) | N::Unit)) => Ok(n),
+        Token::Err(e) => Err(e),
+        _ => Err(ParseError::unexpected(
+            code,
+            start,
+            "expression",
+            "unexpected token",
+        )),
+    }
+}
+
+/// Block after its `{`, or object literal
+fn parse_block(i: &mut usize, code: &[char], pad: usize) -> Result<N, ParseError> {
+    if is_object(*i, code) {
+        return parse_object(i, code, pad);
+    }
+    let mut scope = Vec::new();
+
+    let expr_err = loop {
+        let mut j = *i;
+        let e = parse_expr(&mut j, code, pad + 1);
+        match e {
+            Ok(expr) => {
+                *i = j;
+                scope.push(expr);
+            }
+            Err(e) => {
+                break e;
             }
         }
-        let token = next_token(i, code);
-        if let Token::BlockEnd = token {
-            return Ok(N::Block(scope));
-        } else {
-            return Err("No block end");
-        }
+    };
+    let k = *i;
+    let token = next_token(i, code);
+    match token {
+        Token::BlockEnd => Ok(N::Block(scope)),
+        _ => Err(ParseError::unexpected(code, k, "`}`", "unclosed block").furthest(expr_err)),
     }
+}
 
-    if let Token::ArrayStart = token {
-        let mut es = Vec::new();
+/// Array literal after its `[`
+fn parse_array(i: &mut usize, code: &[char], pad: usize) -> Result<N, ParseError> {
+    let mut es = Vec::new();
 
-        loop {
-            let mut j = *i;
-            let next = parse_expr(&mut j, code, pad);
-            match next {
-                Ok(expr) => {
-                    *i = j;
-                    es.push(expr)
-                }
-                Err(_) => {
-                    let next = next_token(i, code);
-                    match next {
-                        Token::Comma => continue,
-                        Token::ArrayEnd => break,
-                        _ => break,
+    loop {
+        let mut j = *i;
+        let next = parse_expr(&mut j, code, pad + 1);
+        match next {
+            Ok(expr) => {
+                *i = j;
+                es.push(expr)
+            }
+            Err(e) => {
+                let k = *i;
+                let next = next_token(i, code);
+                match next {
+                    Token::Comma => continue,
+                    Token::ArrayEnd => break,
+                    _ => {
+                        return Err(
+                            ParseError::unexpected(code, k, "`]`", "unclosed array").furthest(e)
+                        )
                     }
                 }
             }
         }
-
-        return Ok(N::Array(es));
     }
+    Ok(N::Array(es))
+}
 
-    if let Token::ParStart = token {
-        info!("Function definition start");
-        let mut args_name = Vec::new();
+/// Function definition after its `(`
+fn parse_func(i: &mut usize, code: &[char], pad: usize) -> Result<N, ParseError> {
+    info!("Function definition start");
+    let mut args_name = Vec::new();
+    // Destructured arguments, declared at the start of the body
+    let mut lets = Vec::new();
 
-        loop {
-            let token = next_token(i, code);
-            match token {
-                Token::N(N::Get(name)) => {
-                    info!("name {}", name);
-                    args_name.push(name);
-                }
-                Token::Comma => {}
-                Token::ParEnd => {
-                    break;
-                }
-                _ => {}
+    loop {
+        let k = *i;
+        let token = next_token(i, code);
+        match token {
+            Token::N(N::Get(name, _)) => {
+                info!("name {}", name);
+                args_name.push(name);
+            }
+            Token::ArrayStart | Token::BlockStart => {
+                *i = k;
+                skip_whitespaces(i, code);
+                let start = *i;
+                let pattern = parse_pattern(i, code)?;
+                // Not a valid identifier, can't clash with a variable
+                let name = format!("#{}", args_name.len());
+                let span = Span { start, end: *i };
+                lets.push(N::Destructure(
+                    bx!(pattern),
+                    bx!(N::Get(name.clone(), span)),
+                    span,
+                ));
+                args_name.push(name);
+            }
+            Token::Comma => {}
+            Token::ParEnd => {
+                break;
+            }
+            Token::Err(e) => return Err(e),
+            _ => {
+                return Err(ParseError::unexpected(
+                    code,
+                    k,
+                    "argument name",
+                    "invalid function argument",
+                ))
             }
         }
+    }
 
-        let token = next_token(i, code);
-        if let Token::Assoc = token {
-            let scope = parse_expr(i, code, pad + 1)?;
-            let n = N::FuncDef {
-                args_name,
-                scope: bx!(scope),
-            };
-
-            return Ok(n);
-        } else {
-            return Err("No => after func def");
-        }
+    let k = *i;
+    let token = next_token(i, code);
+    if !matches!(token, Token::Assoc) {
+        return Err(ParseError::unexpected(
+            code,
+            k,
+            "`=>`",
+            "missing `=>` after function arguments",
+        ));
+    }
+    let mut scope = parse_expr(i, code, pad + 1)?;
+    if !lets.is_empty() {
+        lets.push(scope);
+        scope = N::Block(lets);
     }
+    Ok(N::FuncDef {
+        args_name,
+        scope: Rc::new(scope),
+        by_value: false,
+    })
+}
 
-    if let Token::Quoted(s) = token {
-        let n = N::Str(s);
-        return Ok(n);
+/// `move` function, capturing by value
+fn parse_move(i: &mut usize, code: &[char], pad: usize) -> Result<N, ParseError> {
+    let k = *i;
+    match parse_factor(i, code, pad + 1)? {
+        N::FuncDef {
+            args_name, scope, ..
+        } => Ok(N::FuncDef {
+            args_name,
+            scope,
+            by_value: true,
+        }),
+        _ => Err(ParseError::unexpected(
+            code,
+            k,
+            "function",
+            "`move` must be followed by a function",
+        )),
     }
+}
 
-    if let Token::While = token {
-        let condition = parse_expr(i, code, pad + 1)?;
-        let body = parse_expr(i, code, pad + 1)?;
-        let n = N::While {
-            condition: bx!(condition),
-            body: bx!(body),
-        };
+fn parse_while(i: &mut usize, code: &[char], pad: usize) -> Result<N, ParseError> {
+    let condition = parse_expr(i, code, pad + 1)?;
+    let body = parse_expr(i, code, pad + 1)?;
+    Ok(N::While {
+        condition: bx!(condition),
+        body: bx!(body),
+    })
+}
 
-        return Ok(n);
+fn parse_for(i: &mut usize, code: &[char], pad: usize) -> Result<N, ParseError> {
+    let loop_var = |i: &mut usize| {
+        let k = *i;
+        match next_token(i, code) {
+            Token::N(N::Get(name, _)) => Ok(name),
+            Token::Err(e) => Err(e),
+            _ => Err(ParseError::unexpected(
+                code,
+                k,
+                "variable name",
+                "invalid `for` loop variable",
+            )),
+        }
+    };
+    let mut j = *i;
+    let vars = if let Token::ParStart = next_token(&mut j, code) {
+        *i = j;
+        let var = loop_var(i)?;
+        let k = *i;
+        if !matches!(next_token(i, code), Token::Comma) {
+            return Err(ParseError::unexpected(
+                code,
+                k,
+                "`,`",
+                "missing `,` between the `for` loop variables",
+            ));
+        }
+        let index = loop_var(i)?;
+        let k = *i;
+        if !matches!(next_token(i, code), Token::ParEnd) {
+            return Err(ParseError::unexpected(
+                code,
+                k,
+                "`)`",
+                "unclosed `for` loop variables",
+            ));
+        }
+        alloc::vec![var, index]
+    } else {
+        alloc::vec![loop_var(i)?]
+    };
+    let k = *i;
+    if !matches!(next_token(i, code), Token::In) {
+        return Err(ParseError::unexpected(
+            code,
+            k,
+            "`in`",
+            "missing `in` after the `for` loop variable",
+        ));
     }
+    let iter = parse_expr(i, code, pad + 1)?;
+    let body = parse_expr(i, code, pad + 1)?;
+    Ok(N::For {
+        vars,
+        iter: bx!(iter),
+        body: bx!(body),
+    })
+}
 
-    if let Token::If = token {
-        let cond_expr = parse_expr(i, code, pad + 1)?;
-        let true_expr = parse_expr(i, code, pad + 1)?;
+fn parse_match(i: &mut usize, code: &[char], pad: usize) -> Result<N, ParseError> {
+    let value = parse_expr(i, code, pad + 1)?;
+    let k = *i;
+    if !matches!(next_token(i, code), Token::BlockStart) {
+        return Err(ParseError::unexpected(
+            code,
+            k,
+            "`{`",
+            "missing `{` after the `match` value",
+        ));
+    }
+    let mut arms = Vec::new();
+    loop {
         let mut j = *i;
-        let token = next_token(&mut j, code);
-        let else_expr;
-        if let Token::Else = token {
-            *i = j;
-            else_expr = parse_expr(i, code, pad + 1)?;
-        } else {
-            else_expr = N::Unit;
+        match next_token(&mut j, code) {
+            Token::BlockEnd => {
+                *i = j;
+                break;
+            }
+            Token::Comma => {
+                *i = j;
+                continue;
+            }
+            _ => {}
         }
-        let n = N::If {
-            condition: bx!(cond_expr),
-            path_true: bx!(true_expr),
-            path_false: bx!(else_expr),
+        let pattern = parse_pattern(i, code)?;
+        let mut j = *i;
+        let guard = match next_token(&mut j, code) {
+            Token::If => {
+                *i = j;
+                Some(parse_expr(i, code, pad + 1)?)
+            }
+            _ => None,
         };
-        return Ok(n);
+        let k = *i;
+        if !matches!(next_token(i, code), Token::Assoc) {
+            return Err(ParseError::unexpected(
+                code,
+                k,
+                "`=>`",
+                "missing `=>` after the `match` pattern",
+            ));
+        }
+        let body = parse_expr(i, code, pad + 1)?;
+        arms.push(Arm {
+            pattern,
+            guard,
+            body,
+        });
     }
+    Ok(N::Match {
+        value: bx!(value),
+        arms,
+    })
+}
 
-    if let Token::Let(name) = token {
-        let val = parse_expr(i, code, pad + 1)?;
-        let n = N::Set(name, bx!(val));
-        return Ok(n);
+fn parse_if(i: &mut usize, code: &[char], pad: usize) -> Result<N, ParseError> {
+    let cond_expr = parse_expr(i, code, pad + 1)?;
+    let true_expr = parse_expr(i, code, pad + 1)?;
+    let mut j = *i;
+    let token = next_token(&mut j, code);
+    let else_expr;
+    if let Token::Else = token {
+        *i = j;
+        else_expr = parse_expr(i, code, pad + 1)?;
+    } else {
+        else_expr = N::Unit;
     }
+    Ok(N::If {
+        condition: bx!(cond_expr),
+        path_true: bx!(true_expr),
+        path_false: bx!(else_expr),
+    })
+}
 
-    if let Token::N(N::Num(num)) = token {
-        return Ok(N::Num(num));
+/// `throw`, `return` or `break` and their value, from `start`
+fn parse_escape(
+    i: &mut usize,
+    code: &[char],
+    pad: usize,
+    token: Token,
+    start: usize,
+) -> Result<N, ParseError> {
+    if let Token::Throw = token {
+        let val = parse_expr(i, code, pad + 1)?;
+        return Ok(N::Throw(bx!(val), Span { start, end: *i }));
     }
+    // The value is optional
+    let mut j = *i;
+    let val = match parse_expr(&mut j, code, pad + 1) {
+        Ok(val) => {
+            *i = j;
+            val
+        }
+        Err(_) => N::Unit,
+    };
+    let span = Span { start, end: *i };
+    Ok(match token {
+        Token::Return => N::Return(bx!(val), span),
+        _ => N::Break(bx!(val), span),
+    })
+}
 
-    if let Token::N(N::Get(name)) = token {
-        return Ok(N::Get(name));
+fn parse_try(i: &mut usize, code: &[char], pad: usize) -> Result<N, ParseError> {
+    let body = parse_expr(i, code, pad + 1)?;
+    let k = *i;
+    if !matches!(next_token(i, code), Token::Catch) {
+        return Err(ParseError::unexpected(
+            code,
+            k,
+            "`catch`",
+            "missing `catch` after `try`",
+        ));
     }
+    let catch = parse_expr(i, code, pad + 1)?;
+    Ok(N::Try {
+        body: bx!(body),
+        catch: bx!(catch),
+    })
+}
+
+fn parse_let(i: &mut usize, code: &[char], pad: usize, name: String) -> Result<N, ParseError> {
+    let val = parse_expr(i, code, pad + 1)?;
+    Ok(N::Set(name, bx!(val)))
+}
 
-    Err("No term found")
+/// Destructuring `let`, from `start`
+fn parse_let_pattern(
+    i: &mut usize,
+    code: &[char],
+    pad: usize,
+    start: usize,
+) -> Result<N, ParseError> {
+    let pattern = parse_pattern(i, code)?;
+    let k = *i;
+    if !matches!(next_token(i, code), Token::Bin(Op::Assign)) {
+        return Err(ParseError::unexpected(
+            code,
+            k,
+            "`=`",
+            "missing `=` after `let` pattern",
+        ));
+    }
+    let val = parse_expr(i, code, pad + 1)?;
+    Ok(N::Destructure(
+        bx!(pattern),
+        bx!(val),
+        Span { start, end: *i },
+    ))
 }
 
 #[cfg(test)]
@@ -1,13 +1,34 @@
 use super::*;
 
+/// Evaluate with both backends, they must agree
+fn parse_eval(code: &str) -> N {
+    let res = crate::parse_eval(code).unwrap();
+    assert_eq!(res, vm::parse_eval(code).unwrap());
+    res
+}
+
+/// Evaluate with both backends, they must agree
+fn try_parse_eval(code: &str) -> Result<N, RuntimeError> {
+    let res = crate::try_parse_eval(code);
+    match (&res, vm::try_parse_eval(code)) {
+        (Ok(n), Ok(vm_n)) => assert_eq!(n, &vm_n),
+        (Err(e), Err(vm_e)) => {
+            assert_eq!(e.kind, vm_e.kind);
+            assert_eq!(e.span, vm_e.span);
+        }
+        (_, vm_res) => panic!("backends disagree: {:?} {:?}", res, vm_res),
+    }
+    res
+}
+
 #[test]
 fn binary_opt() {
     let code = r#"{1+1}"#;
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 2.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -20,11 +41,11 @@ fn flow_while_0() {
         }
         x
         }"#;
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 5.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -37,11 +58,11 @@ fn flow_if_no_else_0() {
             x
         }
         }"#;
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 6.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 #[test]
@@ -53,12 +74,8 @@ fn flow_if_no_else_1() {
             x
         }
         }"#;
-    let res = parse_eval(&code);
-    if let N::Unit = res {
-        assert!(true)
-    } else {
-        assert!(false)
-    }
+    let res = parse_eval(code);
+    assert_eq!(res, N::Unit);
 }
 
 #[test]
@@ -71,11 +88,11 @@ fn flow_if_else_0() {
         }else 10
         y
         }"#;
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 10.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -89,37 +106,37 @@ fn flow_if_else_1() {
         }else 10
         y
         }"#;
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 6.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
 #[test]
 fn flow_if_else_mini() {
     let code = r#"{
-        let true = 1
-        let y = if true 10 else 20
+        let yes = 1
+        let y = if yes 10 else 20
         y
         }"#;
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 10.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
 #[test]
 fn string_concat() {
     let code = r#"{"hello" +" "+"world"}"#;
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Str(x) = res {
         assert_eq!(x, String::from("hello world"))
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -131,11 +148,11 @@ fn higher_order_func() {
         let g=  (f,e)=> f(e)
         g(f,x)
         }"#;
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 1.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -151,11 +168,11 @@ fn scope() {
             }
         }
         }"#;
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 3.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -169,11 +186,11 @@ fn big_function() {
         }
         f(0,1,2)
         }"#;
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 1003.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -183,11 +200,11 @@ fn smol_function() {
         let f = (a) => 1+a
         f(10)
         }"#;
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 11.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -200,13 +217,25 @@ fn op_equals() {
         }
         x
         }"#;
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 1.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
+
+#[test]
+fn op_equals_mixed_types() {
+    let code = r#"[1 == true, 1 == "a", [1] == ["a"], 1 != "1", {:} == [], unit != 0]"#;
+    let res = try_parse_eval(code).unwrap();
+    let expected = [false, false, false, true, false, true];
+    assert_eq!(
+        res,
+        N::Array(expected.iter().map(|b| N::Bool(*b)).collect())
+    );
+}
+
 #[test]
 fn op_greater() {
     let code = r#"{
@@ -216,11 +245,11 @@ fn op_greater() {
         }
         x
         }"#;
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 1.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -230,12 +259,8 @@ fn missing_args() {
         let f = (a,b,c)=> c
         f(1,2)
         }"#;
-    let res = parse_eval(&code);
-    if let N::Unit = res {
-        assert!(true)
-    } else {
-        assert!(false)
-    }
+    let res = parse_eval(code);
+    assert_eq!(res, N::Unit);
 }
 
 #[test]
@@ -244,11 +269,11 @@ fn over_args() {
         let f = (a,b,c)=> a+b+c
         f(1,2,3,4)
         }"#;
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 6.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -257,11 +282,11 @@ fn op_precedence_0() {
     let code = r#"{
         2+3*4
         }"#;
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 14.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -270,11 +295,11 @@ fn op_precedence_1() {
     let code = r#"{
         3*4+2
         }"#;
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 14.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -283,12 +308,8 @@ fn op_boolean_0() {
     let code = r#"{
         0&1
         }"#;
-    let res = parse_eval(&code);
-    if let N::Num(x) = res {
-        assert_eq!(x, 0.0)
-    } else {
-        assert!(false)
-    }
+    let res = parse_eval(code);
+    assert_eq!(res, N::Bool(false));
 }
 
 #[test]
@@ -296,12 +317,8 @@ fn op_boolean_1() {
     let code = r#"{
         1&0
         }"#;
-    let res = parse_eval(&code);
-    if let N::Num(x) = res {
-        assert_eq!(x, 0.0)
-    } else {
-        assert!(false)
-    }
+    let res = parse_eval(code);
+    assert_eq!(res, N::Bool(false));
 }
 
 #[test]
@@ -309,12 +326,8 @@ fn op_boolean_2() {
     let code = r#"{
         1&1
         }"#;
-    let res = parse_eval(&code);
-    if let N::Num(x) = res {
-        assert_eq!(x, 1.0)
-    } else {
-        assert!(false)
-    }
+    let res = parse_eval(code);
+    assert_eq!(res, N::Bool(true));
 }
 
 #[test]
@@ -322,12 +335,8 @@ fn op_boolean_3() {
     let code = r#"{
         0|1
         }"#;
-    let res = parse_eval(&code);
-    if let N::Num(x) = res {
-        assert_eq!(x, 1.0)
-    } else {
-        assert!(false)
-    }
+    let res = parse_eval(code);
+    assert_eq!(res, N::Bool(true));
 }
 
 #[test]
@@ -335,12 +344,8 @@ fn op_boolean_4() {
     let code = r#"{
         1|0
         }"#;
-    let res = parse_eval(&code);
-    if let N::Num(x) = res {
-        assert_eq!(x, 1.0)
-    } else {
-        assert!(false)
-    }
+    let res = parse_eval(code);
+    assert_eq!(res, N::Bool(true));
 }
 
 #[test]
@@ -348,12 +353,8 @@ fn op_boolean_5() {
     let code = r#"{
         1|1
         }"#;
-    let res = parse_eval(&code);
-    if let N::Num(x) = res {
-        assert_eq!(x, 1.0)
-    } else {
-        assert!(false)
-    }
+    let res = parse_eval(code);
+    assert_eq!(res, N::Bool(true));
 }
 
 #[test]
@@ -361,12 +362,8 @@ fn op_boolean_6() {
     let code = r#"{
         0|0
         }"#;
-    let res = parse_eval(&code);
-    if let N::Num(x) = res {
-        assert_eq!(x, 0.0)
-    } else {
-        assert!(false)
-    }
+    let res = parse_eval(code);
+    assert_eq!(res, N::Bool(false));
 }
 
 #[test]
@@ -376,11 +373,11 @@ fn func_returns_func() {
         let g = f(1)
         g(2)
         }"#;
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 3.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -389,11 +386,11 @@ fn anonymous_func() {
     let code = r#"{
         {(a,b,c)=> a+b+c}(1,2,3)
         }"#;
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 6.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -401,11 +398,11 @@ fn anonymous_func() {
 fn multiple_expr_no_block() {
     let code = r#"let x = 5
     x+2"#;
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 7.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -415,11 +412,11 @@ fn fibonacci() {
 let fib = (e)=> if e<2 e else fib(e-1)+fib(e-2)
 fib(10)
 ";
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 55.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -429,11 +426,11 @@ fn factorial() {
 let fac = (e)=> if e<2 e else fac(e-1)*e
 fac(5)
 ";
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 120.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -447,11 +444,11 @@ let f = {
 }
 f()
 ";
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 7.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -464,11 +461,11 @@ let f = ()=>{
 }
 f()
 ";
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 120.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -482,11 +479,11 @@ fn excluded_from_func_dup() {
     let f = (c) => a+b+c
     f(0)
 ";
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 3.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -498,11 +495,11 @@ fn array_concat() {
     let b = [4,5,6]
     a++b
 ";
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Array(v) = res {
         assert_eq!(v.len(), 6)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -512,11 +509,11 @@ fn array_get() {
     let a = [1,2,3]
     a(1)
 ";
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 2.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -526,12 +523,8 @@ fn array_get_overflow() {
     let a = [1,2,3]
     a(4)
 ";
-    let res = parse_eval(&code);
-    if let N::Unit = res {
-        assert!(true)
-    } else {
-        assert!(false)
-    }
+    let res = parse_eval(code);
+    assert_eq!(res, N::Unit);
 }
 
 #[test]
@@ -540,11 +533,11 @@ fn array_get_underflow() {
     let a = [1,2,3]
     a(0-1)
 ";
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 3.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -554,11 +547,11 @@ fn array_get_get() {
     let a = [1,[2.1,2.2,2.3],3]
     {a(1)}(1)
 ";
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 2.2)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -569,11 +562,11 @@ fn array_push() {
     a = a+4
     a(3)
 ";
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 4.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -584,11 +577,11 @@ fn array_prepend() {
     a = 4 + a
     a(0)
 ";
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 4.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -599,11 +592,11 @@ fn array_push_array() {
     a = a+[4,5,6]
     {a(3)}(1)
 ";
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 5.0)
     } else {
-        assert!(false)
+        panic!()
     }
 }
 
@@ -614,10 +607,1874 @@ fn array_map() {
         [1,2,3]((e) => e*2)
     }(1)
 ";
-    let res = parse_eval(&code);
+    let res = parse_eval(code);
     if let N::Num(x) = res {
         assert_eq!(x, 4.0)
     } else {
-        assert!(false)
+        panic!()
+    }
+}
+
+#[test]
+fn parse_error_position() {
+    let mut ctx = Ctx::new();
+    ctx.insert_code("{\n    let x = 1\n    f(x\n}");
+    let err = ctx.parse_next_expr().unwrap_err();
+    assert_eq!(err.pos, 24);
+    assert_eq!((err.line, err.col), (4, 1));
+    assert_eq!(err.expected, "`)`");
+    assert_eq!(err.found, Some(String::from("}")));
+}
+
+#[test]
+fn parse_error_let() {
+    let mut ctx = Ctx::new();
+    ctx.insert_code("let x 5");
+    let err = ctx.parse_next_expr().unwrap_err();
+    assert_eq!((err.line, err.col), (1, 7));
+    assert_eq!(err.expected, "`=`");
+    assert_eq!(
+        format!("{}", err),
+        "1:7: missing `=` after `let` variable name, expected `=`, found `5`"
+    );
+}
+
+#[test]
+fn parse_error_eof() {
+    let mut ctx = Ctx::new();
+    ctx.insert_code("{ let x = 1 ");
+    let err = ctx.parse_next_expr().unwrap_err();
+    assert!(err.is_eof());
+    // The code is kept, more input can complete it
+    ctx.insert_code("x }");
+    assert!(ctx.parse_next_expr().is_ok());
+}
+
+#[test]
+fn runtime_error_undefined_variable() {
+    let code = "
+    let a = 1
+    a + b
+";
+    let err = try_parse_eval(code).unwrap_err();
+    assert_eq!(
+        err.kind,
+        RuntimeErrorKind::UndefinedVariable(String::from("b"))
+    );
+    // Counts from the start of the script, not of the expression
+    assert_eq!(err.span, Span { start: 23, end: 24 });
+    assert_eq!(code.find('b'), Some(23));
+}
+
+#[test]
+fn error_positions_from_script_start() {
+    let err = try_parse_eval("let a = 1\na + b").unwrap_err();
+    assert_eq!(err.span, Span { start: 14, end: 15 });
+    let err = try_parse_eval(r#"let a = 1 1 - "x""#).unwrap_err();
+    assert_eq!(err.span, Span { start: 10, end: 17 });
+    let err = try_parse_eval("let a = 1\nlet b = 2 ]").unwrap_err();
+    let RuntimeErrorKind::Parse(parse) = err.kind else {
+        panic!("not a parse error")
+    };
+    assert_eq!((parse.pos, parse.line, parse.col), (20, 2, 11));
+    // The positions go on across inserted code
+    let mut ctx = Ctx::new();
+    ctx.insert_code("let a = 1\n");
+    let expr = ctx.parse_next_expr().unwrap();
+    try_eval(&expr, &mut ctx).unwrap();
+    ctx.insert_code("a + c");
+    let expr = ctx.parse_next_expr().unwrap();
+    assert_eq!(
+        try_eval(&expr, &mut ctx).unwrap_err().span,
+        Span { start: 14, end: 15 }
+    );
+}
+
+#[test]
+fn runtime_error_type_mismatch() {
+    let code = r#"{ 1 - "a" }"#;
+    let err = try_parse_eval(code).unwrap_err();
+    assert_eq!(
+        err.kind,
+        RuntimeErrorKind::TypeMismatch {
+            op: Op::Minus,
+            lhs: "number",
+            rhs: "string"
+        }
+    );
+    assert_eq!(err.span, Span { start: 2, end: 9 });
+}
+
+#[test]
+fn runtime_error_not_callable() {
+    let code = "
+    let a = 1
+    a(2)
+";
+    let err = try_parse_eval(code).unwrap_err();
+    assert_eq!(err.kind, RuntimeErrorKind::NotCallable("number"));
+}
+
+#[test]
+fn runtime_error_index_out_of_bounds() {
+    let code = "
+    let a = [1,2,3]
+    a(0-4)
+";
+    let err = try_parse_eval(code).unwrap_err();
+    assert_eq!(
+        err.kind,
+        RuntimeErrorKind::IndexOutOfBounds { index: -1, len: 3 }
+    );
+}
+
+#[test]
+fn runtime_error_keeps_ctx_consistent() {
+    let mut ctx = Ctx::new();
+    ctx.insert_code("let a = 1 { let b = 2 let f = (c) => { let d = 3 c + e } f(1) }");
+    let set = ctx.parse_next_expr().unwrap();
+    try_eval(&set, &mut ctx).unwrap();
+    let block = ctx.parse_next_expr().unwrap();
+    assert!(try_eval(&block, &mut ctx).is_err());
+    assert_eq!(ctx.idents, ["a"]);
+    assert_eq!(ctx.deep, 0);
+    // Lenient eval still defaults to N::Unit: 1 + N::Unit
+    if let N::Num(x) = eval(&block, &mut ctx).unwrap() {
+        assert_eq!(x, 1.0)
+    } else {
+        panic!()
+    }
+}
+
+#[test]
+fn try_catch_throw() {
+    let code = r#"
+    let f = (x) => if x > 2 throw "too big" else x
+    try f(3) catch (e) => e + "!"
+"#;
+    let res = parse_eval(code);
+    if let N::Str(x) = res {
+        assert_eq!(x, "too big!")
+    } else {
+        panic!()
+    }
+}
+
+#[test]
+fn try_no_error() {
+    let code = r#"
+    try { 1 + 1 } catch (e) => 0
+"#;
+    let res = parse_eval(code);
+    if let N::Num(x) = res {
+        assert_eq!(x, 2.0)
+    } else {
+        panic!()
+    }
+}
+
+#[test]
+fn try_catch_runtime_error() {
+    let code = r#"
+    try {
+        let a = 1
+        a - "b"
+    } catch (e) => e
+"#;
+    let res = parse_eval(code);
+    if let N::Str(x) = res {
+        assert_eq!(x, "cannot apply Minus to number and string")
+    } else {
+        panic!()
+    }
+}
+
+#[test]
+fn try_rethrow() {
+    let code = r#"
+    try {
+        try throw 1 catch (e) => throw e + 1
+    } catch (e) => e * 10
+"#;
+    let res = parse_eval(code);
+    if let N::Num(x) = res {
+        assert_eq!(x, 20.0)
+    } else {
+        panic!()
+    }
+}
+
+#[test]
+fn throw_uncaught() {
+    let code = "
+    let a = [1,2]
+    a & (e) => if e > 1 throw e else 1
+";
+    let err = try_parse_eval(code).unwrap_err();
+    assert_eq!(err.kind, RuntimeErrorKind::Thrown(N::Num(2.0)));
+    assert_eq!(err.span, Span { start: 43, end: 50 });
+    assert_eq!(code.find("throw e"), Some(43));
+    // Reaches the host without `try_`, where other errors evaluate to N::Unit
+    for run in [crate::parse_eval, vm::parse_eval] {
+        let err = run(code).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::Thrown(N::Num(2.0)));
+        assert_eq!(err.span, Span { start: 43, end: 50 });
+        assert_eq!(run("let a = 1 - \"x\" a").unwrap(), N::Unit);
+    }
+}
+
+#[test]
+fn try_parse_eval_parse_error() {
+    let err = try_parse_eval("let a = 1\na + ]").unwrap_err();
+    let RuntimeErrorKind::Parse(parse) = &err.kind else {
+        panic!("not a parse error: {:?}", err)
+    };
+    assert_eq!((parse.line, parse.found.as_deref()), (2, Some("]")));
+    let err = try_parse_eval("1 +").unwrap_err();
+    assert!(matches!(err.kind, RuntimeErrorKind::Parse(e) if e.is_eof()));
+    // Only whitespace and comments left is the end of the script
+    assert_eq!(try_parse_eval("1 + 1\n// done\n").unwrap(), N::Num(2.0));
+    assert_eq!(try_parse_eval(" ").unwrap(), N::Unit);
+    // Stops at the first parse error
+    assert_eq!(parse_eval("1 ] 2"), N::Num(1.0));
+}
+
+#[test]
+fn fuel_stops_infinite_loop() {
+    let mut ctx = Ctx::new();
+    ctx.fuel = Some(1000);
+    ctx.insert_code("let x = 1 { let y = 2 try { while 1 { x = x + y } } catch (e) => 0 } x");
+    let set = ctx.parse_next_expr().unwrap();
+    try_eval(&set, &mut ctx).unwrap();
+    let block = ctx.parse_next_expr().unwrap();
+    let err = try_eval(&block, &mut ctx).unwrap_err();
+    assert_eq!(err.kind, RuntimeErrorKind::OutOfFuel);
+    assert_eq!(ctx.fuel, Some(0));
+    assert_eq!(ctx.idents, ["x"]);
+    assert_eq!(ctx.deep, 0);
+
+    // Refuel and keep going
+    ctx.fuel = Some(10);
+    let get = ctx.parse_next_expr().unwrap();
+    if let N::Num(x) = try_eval(&get, &mut ctx).unwrap() {
+        assert!(x > 100.0)
+    } else {
+        panic!()
+    }
+}
+
+#[test]
+fn error_forgets_declared_variables() {
+    let code = r#"
+    let y = - let z = false
+    try [let a = 1, throw 2] catch (e) => 0
+    let b = 1 { let c = 2 1 - "x" }
+    [y, z, a, b, c]
+"#;
+    let run = |eval: fn(&N, &mut Ctx) -> Result<N, RuntimeError>| {
+        let mut ctx = Ctx::new();
+        ctx.insert_code(code);
+        let mut res = Vec::new();
+        while let Ok(expr) = ctx.parse_next_expr() {
+            res.push(eval(&expr, &mut ctx).map_err(|e| e.kind));
+        }
+        (res, ctx.idents)
+    };
+    let (res, idents) = run(try_eval);
+    assert_eq!((res.clone(), idents.clone()), run(vm::try_eval));
+    // Only `let b = 1` ran to the end
+    assert_eq!(idents, ["b"]);
+    assert_eq!(
+        res[4],
+        Err(RuntimeErrorKind::UndefinedVariable(String::from("y")))
+    );
+    assert_eq!(res[1], Ok(N::Num(0.0)));
+}
+
+#[test]
+fn fuel_span_and_state() {
+    let code = "{ let x = 0 let f = (n) => n + 1 while 1 x = f(x) }";
+    let mut results = Vec::new();
+    for eval in [try_eval, vm::try_eval] {
+        let mut ctx = Ctx::new();
+        ctx.fuel = Some(100);
+        ctx.insert_code(code);
+        let expr = ctx.parse_next_expr().unwrap();
+        let err = eval(&expr, &mut ctx).unwrap_err();
+        assert!(ctx.idents.is_empty());
+        assert_eq!(ctx.mem, 0);
+        results.push((err.kind, err.span, ctx.deep));
+    }
+    // Where the fuel runs out depends on the backend, not the error
+    assert_eq!(results[0], results[1]);
+    assert_eq!(results[0].0, RuntimeErrorKind::OutOfFuel);
+    assert_eq!(results[0].1, Span::default());
+    let mut ctx = Ctx::new();
+    ctx.fuel = Some(100);
+    ctx.insert_code("1 + { while 1 1 }");
+    let expr = ctx.parse_next_expr().unwrap();
+    let span = try_eval(&expr, &mut ctx).unwrap_err().span;
+    ctx.fuel = Some(100);
+    assert_eq!(vm::try_eval(&expr, &mut ctx).unwrap_err().span, span);
+    assert_eq!(span, Span { start: 0, end: 17 });
+}
+
+#[test]
+fn fuel_enough() {
+    let mut ctx = Ctx::new();
+    ctx.fuel = Some(1000);
+    ctx.insert_code("{ let x = 0 while x < 10 x = x + 1 x }");
+    let expr = ctx.parse_next_expr().unwrap();
+    if let N::Num(x) = try_eval(&expr, &mut ctx).unwrap() {
+        assert_eq!(x, 10.0)
+    } else {
+        panic!()
+    }
+    assert!(ctx.fuel.unwrap() < 1000);
+}
+
+#[test]
+fn stack_overflow() {
+    let code = "
+    let f = (n) => f(n)
+    f(1)
+";
+    let err = try_parse_eval(code).unwrap_err();
+    assert_eq!(err.kind, RuntimeErrorKind::StackOverflow);
+    // A limit isn't a runtime error that evaluates to N::Unit
+    for run in [crate::parse_eval, vm::parse_eval] {
+        assert_eq!(run(code).unwrap_err().kind, RuntimeErrorKind::StackOverflow);
+    }
+}
+
+#[test]
+fn deep_recursion_default() {
+    let code = "{ let f = (n) => if n < 1 0 else 1 + f(n-1) f(100) }";
+    assert_eq!(try_parse_eval(code).unwrap(), N::Num(100.0));
+    let code = "{ let f = (n) => { if n < 1 { 0 } else { let x = n 1 + f(x-1) } } f(100) }";
+    assert_eq!(try_parse_eval(code).unwrap(), N::Num(100.0));
+    let code = "{ let f = (n) => if n < 1 0 else 1 + f(n-1) f(200) }";
+    assert_eq!(
+        try_parse_eval(code).unwrap_err().kind,
+        RuntimeErrorKind::StackOverflow
+    );
+}
+
+#[test]
+fn stack_overflow_uncatchable() {
+    let mut ctx = Ctx::new();
+    ctx.max_deep = 50;
+    ctx.insert_code(
+        "{ let f = (n) => if n < 1 0 else f(n-1) + 1 try f(5) + f(100) catch (e) => e }",
+    );
+    let expr = ctx.parse_next_expr().unwrap();
+    for res in [try_eval(&expr, &mut ctx), vm::try_eval(&expr, &mut ctx)] {
+        assert_eq!(res.unwrap_err().kind, RuntimeErrorKind::StackOverflow);
+        assert_eq!(ctx.deep, 0);
+        assert!(ctx.idents.is_empty());
+    }
+    // Would retry exponentially many times if it was caught
+    let code = "{ let f = (n) => try f(n) catch (e) => f(n) f(0) }";
+    assert_eq!(
+        try_parse_eval(code).unwrap_err().kind,
+        RuntimeErrorKind::StackOverflow
+    );
+}
+
+#[test]
+fn nesting_limit() {
+    let mut ctx = Ctx::new();
+    ctx.max_nesting = 50;
+    ctx.insert_code("{ let f = (n) => if n < 1 0 else { { { f(n-1) + 1 } } } f(20) }");
+    let expr = ctx.parse_next_expr().unwrap();
+    let err = try_eval(&expr, &mut ctx).unwrap_err();
+    assert_eq!(err.kind, RuntimeErrorKind::StackOverflow);
+    assert_eq!(ctx.deep, 0);
+    // The Vm doesn't nest on the native stack
+    assert_eq!(vm::try_eval(&expr, &mut ctx).unwrap(), N::Num(20.0));
+}
+
+#[test]
+fn parse_nesting_limit() {
+    extern crate std;
+    // Reaching the limit takes more than the 2MB of a test thread in debug builds
+    let parse = |code: String| {
+        std::thread::Builder::new()
+            .stack_size(16 << 20)
+            .spawn(move || {
+                let mut ctx = Ctx::new();
+                ctx.insert_code(&code);
+                ctx.parse_next_expr()
+                    .map(|n| eval(&n, &mut ctx).unwrap() == N::Num(1.0))
+            })
+            .unwrap()
+            .join()
+            .unwrap()
+    };
+    let nested = |n| "{".repeat(n) + "1" + &"}".repeat(n);
+    assert!(parse(nested(100)).unwrap());
+    for code in [
+        nested(400),
+        "1+".repeat(2000) + "1",
+        String::from("o") + &".a".repeat(2000),
+    ] {
+        let err = parse(code).unwrap_err();
+        assert_eq!(err.msg, "expression nested too deeply");
+    }
+}
+
+#[test]
+fn memory_limit_array() {
+    let mut ctx = Ctx::new();
+    ctx.max_mem = Some(100_000);
+    ctx.insert_code("{ let a = [1] try { while 1 a = a ++ a } catch (e) => 0 }");
+    let expr = ctx.parse_next_expr().unwrap();
+    let err = try_eval(&expr, &mut ctx).unwrap_err();
+    assert_eq!(err.kind, RuntimeErrorKind::MemoryLimitExceeded);
+    assert!(ctx.idents.is_empty());
+    assert_eq!(ctx.mem, 0);
+}
+
+#[test]
+fn memory_limit_string() {
+    let mut ctx = Ctx::new();
+    ctx.max_mem = Some(1000);
+    ctx.insert_code(r#"let s = "abcd" while 1 s = s + s"#);
+    let set = ctx.parse_next_expr().unwrap();
+    try_eval(&set, &mut ctx).unwrap();
+    let expr = ctx.parse_next_expr().unwrap();
+    let err = try_eval(&expr, &mut ctx).unwrap_err();
+    assert_eq!(err.kind, RuntimeErrorKind::MemoryLimitExceeded);
+    // Only the last valid string is held
+    assert_eq!(ctx.mem, 512);
+}
+
+#[test]
+fn memory_limit_enough() {
+    let mut ctx = Ctx::new();
+    ctx.max_mem = Some(100_000);
+    ctx.insert_code(
+        "{ let a = [] let i = 0 while i < 100 { a = a + i i = i + 1 } a | (x, y) => x + y }",
+    );
+    let expr = ctx.parse_next_expr().unwrap();
+    assert_eq!(try_eval(&expr, &mut ctx).unwrap(), N::Num(4950.0));
+    assert_eq!(ctx.mem, 0);
+}
+
+#[test]
+fn interrupt() {
+    let mut ctx = Ctx::new();
+    let flag = Rc::new(Cell::new(false));
+    ctx.interrupt = Some(flag.clone());
+    let ticks = Rc::new(Cell::new(0));
+    let (f, t) = (flag.clone(), ticks.clone());
+    let tick = Rc::new(move |_, _, _, _| {
+        t.set(t.get() + 1);
+        if t.get() == 10 {
+            // Ctrl-C
+            f.set(true);
+        }
+        N::Unit
+    });
+    ctx.set_val("tick", N::FuncNativeDef(Native(tick)));
+    ctx.insert_code(
+        "{ let x = 0 let f = () => { let y = 1 while 1 { tick() } } try f() catch (e) => 0 } 5",
+    );
+    let expr = ctx.parse_next_expr().unwrap();
+    let err = try_eval(&expr, &mut ctx).unwrap_err();
+    assert_eq!(err.kind, RuntimeErrorKind::Interrupted);
+    assert_eq!(ticks.get(), 10);
+    assert!(!flag.get());
+    assert_eq!(ctx.idents, ["tick"]);
+    assert_eq!(ctx.deep, 0);
+
+    let expr = ctx.parse_next_expr().unwrap();
+    assert_eq!(try_eval(&expr, &mut ctx).unwrap(), N::Num(5.0));
+}
+
+#[test]
+fn vm_resume() {
+    let mut ctx = Ctx::new();
+    ctx.insert_code(
+        "{ let x = 0 let f = (e) => e + 1 while x < 100 x = f(x) try x catch (e) => 0 }",
+    );
+    let expr = ctx.parse_next_expr().unwrap();
+    let mut vm = vm::Vm::new(&expr);
+    let mut runs = 0;
+    let res = loop {
+        ctx.fuel = Some(50);
+        runs += 1;
+        match vm.try_eval(&mut ctx) {
+            Ok(res) => break res,
+            Err(e) => {
+                assert_eq!(e.kind, RuntimeErrorKind::OutOfFuel);
+                assert!(vm.is_suspended());
+                assert_eq!(ctx.deep, 0);
+            }
+        }
+    };
+    assert_eq!(res, N::Num(100.0));
+    assert!(runs > 10);
+    assert!(ctx.idents.is_empty());
+
+    // Runs again from the start
+    ctx.fuel = None;
+    assert_eq!(vm.eval(&mut ctx).unwrap(), N::Num(100.0));
+}
+
+#[test]
+fn vm_resume_callbacks() {
+    let mut ctx = Ctx::new();
+    let ticks = Rc::new(Cell::new(0));
+    let t = ticks.clone();
+    let tick = Rc::new(move |_, _, _, _| {
+        t.set(t.get() + 1);
+        N::Unit
+    });
+    ctx.set_val("tick", N::FuncNativeDef(Native(tick)));
+    ctx.insert_code(
+        r#"{
+        tick()
+        let a = [1,2,3,4,5,6,7,8,9,10]((x) => x * 2) & (x) => x > 4
+        let o = {"a": 1, "b": 2}((x) => x + 1)
+        let l = [0]
+        let s = a | (acc, x) => acc + x
+        l << s
+        l(0) = o.b
+        l
+    }"#,
+    );
+    let expr = ctx.parse_next_expr().unwrap();
+    let mut vm = vm::Vm::new(&expr);
+    // Runs out in the callbacks too, and continues there
+    let res = (0..1000).find_map(|_| {
+        ctx.fuel = Some(5);
+        vm.try_eval(&mut ctx).ok()
+    });
+    assert_eq!(res, Some(N::Array(alloc::vec![N::Num(3.0), N::Num(104.0)])));
+    assert_eq!(ticks.get(), 1);
+    assert_eq!(ctx.idents, ["tick"]);
+}
+
+#[test]
+fn callbacks_unwind() {
+    let code = r#"{
+        let f = (x) => if x == 2 throw x * 10 else x
+        let a = try [1,2,3](f) catch (e) => e
+        let b = [1,2,3]((x) => try f(x) catch (e) => e)
+        let c = [1,2,3] & (x) => { return x > 1 }
+        let d = try [1,2,3] | (acc, x) => f(x) catch (e) => e + 1
+        [a, b, c, d]
+    }"#;
+    assert_eq!(parse_eval(code), parse_eval("[20, [1, 20, 3], [2, 3], 21]"));
+}
+
+#[test]
+fn vm_deep_recursion() {
+    let mut ctx = Ctx::new();
+    ctx.max_deep = 10_000;
+    ctx.insert_code("{ let f = (n) => if n < 1 0 else f(n-1) + 1 f(5000) }");
+    let expr = ctx.parse_next_expr().unwrap();
+    assert_eq!(vm::try_eval(&expr, &mut ctx).unwrap(), N::Num(5000.0));
+    assert_eq!(ctx.deep, 0);
+}
+
+#[test]
+fn vm_catch_across_calls() {
+    let mut ctx = Ctx::new();
+    ctx.max_deep = 50;
+    ctx.insert_code(
+        "{ let f = (n) => if n < 1 throw 7 else { let y = n f(n-1) } try f(10) catch (e) => e + 1 }",
+    );
+    let expr = ctx.parse_next_expr().unwrap();
+    assert_eq!(vm::try_eval(&expr, &mut ctx).unwrap(), N::Num(8.0));
+    assert_eq!(ctx.deep, 0);
+    assert!(ctx.idents.is_empty());
+}
+
+#[test]
+fn resolve_locals() {
+    let mut ctx = Ctx::new();
+    ctx.insert_code("{ let x = 0 let f = (a) => a + x f(x) }");
+    let expr = ctx.parse_next_expr().unwrap();
+    let N::Block(arr) = expr else {
+        panic!("not a block")
+    };
+    let N::Set(_, f) = &arr[1] else {
+        panic!("not a let")
+    };
+    let N::FuncDef { scope, .. } = f.as_ref() else {
+        panic!("not a function")
+    };
+    let N::Binary(_, a, x, _) = scope.as_ref() else {
+        panic!("not a binary")
+    };
+    assert_eq!(**a, N::Local { depth: 0, slot: 0 });
+    assert_eq!(**x, N::Local { depth: 1, slot: 0 });
+}
+
+#[test]
+fn resolve_globals() {
+    let mut ctx = Ctx::new();
+    ctx.set_val("g", N::Num(3.0));
+    ctx.insert_code("let x = 2 { let y = g * x g = y y + g }");
+    let set = ctx.parse_next_expr().unwrap();
+    eval(&set, &mut ctx).unwrap();
+    let expr = ctx.parse_next_expr().unwrap();
+    assert_eq!(eval(&expr, &mut ctx).unwrap(), N::Num(12.0));
+    assert_eq!(ctx.find_var("g").unwrap().1, &N::Num(6.0));
+}
+
+#[test]
+fn resolve_shadowing() {
+    let code = "{ let y = 5 let f = (x) => { let y = 2 let g = (y) => x + y g(10) + y } f(1) }";
+    let res = parse_eval(code);
+    if let N::Num(x) = res {
+        assert_eq!(x, 13.0)
+    } else {
+        panic!()
+    }
+}
+
+#[test]
+fn resolve_loop_declaration() {
+    let code = "{ let i = 0 let a = [] while { a = a + i  i < 3 } let i = i + 1  a }";
+    let res = parse_eval(code);
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![
+            N::Num(0.0),
+            N::Num(1.0),
+            N::Num(2.0),
+            N::Num(3.0)
+        ])
+    );
+}
+
+#[test]
+fn closure_counter() {
+    let code = r#"
+    let c = 0
+    let inc = () => c = c + 1
+    inc()
+    inc()
+    c
+"#;
+    let res = parse_eval(code);
+    if let N::Num(x) = res {
+        assert_eq!(x, 2.0)
+    } else {
+        panic!()
+    }
+}
+
+#[test]
+fn closure_shared() {
+    let code = r#"{
+    let make = () => {
+        let n = 0
+        [() => n = n + 1, () => n]
+    }
+    let fs = make()
+    let inc = fs(0)
+    let get = fs(1)
+    inc()
+    inc()
+    get()
+}"#;
+    let res = parse_eval(code);
+    if let N::Num(x) = res {
+        assert_eq!(x, 2.0)
+    } else {
+        panic!()
+    }
+}
+
+#[test]
+fn closure_move() {
+    let code = r#"{
+    let c = 1
+    let by_ref = () => c
+    let by_value = move () => c
+    c = 5
+    [by_ref(), by_value()]
+}"#;
+    let res = parse_eval(code);
+    assert_eq!(res, N::Array(alloc::vec![N::Num(5.0), N::Num(1.0)]));
+}
+
+#[test]
+fn object_field() {
+    let code = r#"{
+    let o = {x: 1, "y z": {w: 2}}
+    o.x + o("y z").w
+}"#;
+    let res = parse_eval(code);
+    if let N::Num(x) = res {
+        assert_eq!(x, 3.0)
+    } else {
+        panic!()
+    }
+}
+
+#[test]
+fn object_missing_field() {
+    assert_eq!(parse_eval("{a: 1}.b"), N::Unit);
+    let err = try_parse_eval("{a: 1}(0)").unwrap_err();
+    assert_eq!(
+        err.kind,
+        RuntimeErrorKind::InvalidIndex {
+            target: "object",
+            index: "number"
+        }
+    );
+}
+
+#[test]
+fn object_assign() {
+    let code = r#"{
+    let o = {a: 1, b: {c: [1, 2]}}
+    o.a = 5
+    o.b.c(1) = 7
+    o("d") = 9
+    o.a + o.b.c(1) + o.d
+}"#;
+    let res = parse_eval(code);
+    if let N::Num(x) = res {
+        assert_eq!(x, 21.0)
+    } else {
+        panic!()
+    }
+}
+
+#[test]
+fn object_keys_map() {
+    let res = parse_eval("{b: 1, a: 2}()");
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![
+            N::Str(String::from("a")),
+            N::Str(String::from("b"))
+        ])
+    );
+    let res = parse_eval("{a: 1, b: 2}((v, k) => v * 10).b");
+    assert_eq!(res, N::Num(20.0));
+}
+
+#[test]
+fn object_merge() {
+    let code = r#"{
+    let o = {a: 1, b: 2} ++ {b: 3}
+    o = o + ["c", 4]
+    o.a + o.b + o.c
+}"#;
+    let res = parse_eval(code);
+    if let N::Num(x) = res {
+        assert_eq!(x, 8.0)
+    } else {
+        panic!()
+    }
+}
+
+#[test]
+fn object_not_block() {
+    assert_eq!(parse_eval("{}"), N::Unit);
+    assert_eq!(parse_eval("{:}"), N::Object(BTreeMap::new()));
+    assert_eq!(parse_eval("{ let a = 1 { a: a }.a }"), N::Num(1.0));
+}
+
+#[test]
+fn string_escapes() {
+    let res = parse_eval(r#""a\"b\\c\n\t\u{48}\u{1F600}""#);
+    assert_eq!(res, N::Str(String::from("a\"b\\c\n\tH\u{1F600}")));
+}
+
+#[test]
+fn string_raw() {
+    let res = parse_eval(r####"[r"C:\path\n", r#"say "hi""#]"####);
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![
+            N::Str(String::from(r"C:\path\n")),
+            N::Str(String::from(r#"say "hi""#))
+        ])
+    );
+}
+
+#[test]
+fn string_invalid_escape() {
+    let mut ctx = Ctx::new();
+    ctx.insert_code("{\n  f(\"ok\\q\")\n}");
+    let err = ctx.parse_next_expr().unwrap_err();
+    assert_eq!((err.line, err.col), (2, 8));
+    assert_eq!(err.found, Some(String::from("\\q")));
+    assert_eq!(
+        format!("{}", err),
+        "2:8: invalid escape in string, expected escape sequence, found `\\q`"
+    );
+
+    let mut ctx = Ctx::new();
+    ctx.insert_code(r#""\u{110000}""#);
+    let err = ctx.parse_next_expr().unwrap_err();
+    assert_eq!(err.msg, "invalid unicode escape");
+    assert_eq!(err.found, Some(String::from(r"\u{110000}")));
+}
+
+#[test]
+fn string_unterminated_escape() {
+    let mut ctx = Ctx::new();
+    ctx.insert_code("\"a\\");
+    let err = ctx.parse_next_expr().unwrap_err();
+    assert!(err.is_eof());
+    ctx.insert_code("n\"");
+    assert_eq!(
+        eval(&ctx.parse_next_expr().unwrap(), &mut ctx).unwrap(),
+        N::Str(String::from("a\n"))
+    );
+}
+
+#[test]
+fn template_string() {
+    let code = r#"{
+    let x = 41
+    let name = "fomo"
+    `${name}: ${x + 1}, ${[1]}\${x}`
+}"#;
+    let res = parse_eval(code);
+    assert_eq!(res, N::Str(String::from("fomo: 42, Array([Num(1.0)])${x}")));
+    assert_eq!(parse_eval("`a\nb`"), N::Str(String::from("a\nb")));
+}
+
+#[test]
+fn template_string_closure() {
+    let code = r#"{
+    let n = 1
+    let f = () => `n=${n}`
+    n = 2
+    f()
+}"#;
+    assert_eq!(parse_eval(code), N::Str(String::from("n=2")));
+}
+
+#[test]
+fn template_string_error() {
+    let mut ctx = Ctx::new();
+    ctx.insert_code("`a ${1 + 2 b`");
+    let err = ctx.parse_next_expr().unwrap_err();
+    assert_eq!(err.msg, "unclosed template expression");
+    assert_eq!(err.pos, 11);
+
+    let err = try_parse_eval("`${1 - \"a\"}`").unwrap_err();
+    assert_eq!(err.span, Span { start: 3, end: 10 });
+}
+
+#[test]
+fn comments_skipped() {
+    let code = r#"{
+    // counter
+    let x = 0 /* start /* nested */ still comment */
+    while x < 5 { // loop
+        x = x + 1 // step
+    }
+    x / 1 // "not a string
+}"#;
+    let res = parse_eval(code);
+    if let N::Num(x) = res {
+        assert_eq!(x, 5.0)
+    } else {
+        panic!()
+    }
+    assert_eq!(
+        parse_eval(r#""// not /* a comment""#),
+        N::Str(String::from("// not /* a comment"))
+    );
+}
+
+#[test]
+fn comments_unterminated() {
+    let mut ctx = Ctx::new();
+    ctx.insert_code("1 + /* more");
+    let err = ctx.parse_next_expr().unwrap_err();
+    assert!(err.is_eof());
+    assert_eq!((err.expected, err.col), ("`*/`", 5));
+    // More input completes it
+    ctx.insert_code(" */ 2");
+    assert_eq!(
+        eval(&ctx.parse_next_expr().unwrap(), &mut ctx).unwrap(),
+        N::Num(3.0)
+    );
+    // Even after a whole expression
+    let err = try_parse_eval("1 /* more /* */").unwrap_err();
+    assert!(matches!(err.kind, RuntimeErrorKind::Parse(e) if e.expected == "`*/`" && e.is_eof()));
+    assert_eq!(
+        comments("1 /* more"),
+        alloc::vec![Span { start: 2, end: 9 }]
+    );
+}
+
+#[test]
+fn comments_spans() {
+    let code = "let a = \"//\" // one\n/* two /* three */ */ a";
+    let spans = comments(code);
+    assert_eq!(
+        spans,
+        alloc::vec![Span { start: 13, end: 19 }, Span { start: 20, end: 41 }]
+    );
+}
+
+#[test]
+fn unary_minus() {
+    let code = r#"{
+    let x = 3
+    let f = (e) => e * 2
+    [-x, -3, - -x, -x * 2, 1 - -x, -f(x), +x, [1, -2](1)]
+}"#;
+    let res = parse_eval(code);
+    assert_eq!(
+        res,
+        N::Array(
+            [-3.0, -3.0, 3.0, -6.0, 4.0, -6.0, 3.0, -2.0]
+                .iter()
+                .map(|x| N::Num(*x))
+                .collect()
+        )
+    );
+}
+
+#[test]
+fn unary_not() {
+    let code = r#"{
+    let flag = 0
+    [!flag, !1, !!"a", !flag & 1, ![]]
+}"#;
+    let res = parse_eval(code);
+    assert_eq!(
+        res,
+        N::Array(
+            [true, false, true, true, true]
+                .iter()
+                .map(|x| N::Bool(*x))
+                .collect()
+        )
+    );
+}
+
+#[test]
+fn unary_type_mismatch() {
+    let err = try_parse_eval(r#"{ let s = "a" [-s] }"#).unwrap_err();
+    assert_eq!(
+        err.kind,
+        RuntimeErrorKind::InvalidOperand {
+            op: Op::Minus,
+            operand: "string"
+        }
+    );
+    assert_eq!(err.span, Span { start: 15, end: 17 });
+}
+
+#[test]
+fn precedence_left_associative() {
+    let res = parse_eval("[10 - 2 - 3, 64 / 4 / 2, 10 - 2 + 3, 7 % 4 * 2, 2 * 7 % 4]");
+    assert_eq!(
+        res,
+        N::Array(
+            [5.0, 8.0, 11.0, 6.0, 2.0]
+                .iter()
+                .map(|x| N::Num(*x))
+                .collect()
+        )
+    );
+}
+
+#[test]
+fn precedence_levels() {
+    let code = r#"{
+    let x = 2
+    let y = 3
+    [1 + 2 * 3 - 4 % 3, x < y == true, x + 1 == y & y > x, x == 3 | y == 3, 1 + x * y < 7]
+}"#;
+    let res = parse_eval(code);
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![
+            N::Num(6.0),
+            N::Bool(true),
+            N::Bool(true),
+            N::Bool(true),
+            N::Bool(false)
+        ])
+    );
+}
+
+#[test]
+fn precedence_assign_not_chained() {
+    let mut ctx = Ctx::new();
+    ctx.insert_code("{ let a = 0 let b = 0 a = b = 1 + 1 }");
+    let err = ctx.parse_next_expr().unwrap_err();
+    assert_eq!(err.msg, "chained assignment");
+    assert_eq!((err.line, err.col), (1, 29));
+    let res = parse_eval("{ let a = 0 let b = 0 b = 1 + 1 a = b == 2 [a, b] }");
+    assert_eq!(res, N::Array(alloc::vec![N::Bool(true), N::Num(2.0)]));
+}
+
+#[test]
+fn short_circuit() {
+    let code = r#"{
+    let calls = 0
+    let f = (r) => { calls = calls + 1 r }
+    let x = 0
+    [x != 0 & 10 / x > 1, f(1) | f(1), f(0) & f(1), 0 | f(1), calls]
+}"#;
+    let res = try_parse_eval(code).unwrap();
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![
+            N::Bool(false),
+            N::Bool(true),
+            N::Bool(false),
+            N::Bool(true),
+            N::Num(3.0)
+        ])
+    );
+    // The right side would throw
+    let res = try_parse_eval(r#"[1 || throw "no", 0 && throw "no", 1 | throw "no"]"#).unwrap();
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![N::Bool(true), N::Bool(false), N::Bool(true)])
+    );
+}
+
+#[test]
+fn short_circuit_logical_only() {
+    // `&&` and `||` don't filter nor reduce arrays
+    let res = parse_eval("[[1, 2] && (e) => e < 2, [] || [1], [1, 2] & (e) => e < 2]");
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![
+            N::Bool(false),
+            N::Bool(true),
+            N::Array(alloc::vec![N::Num(1.0)])
+        ])
+    );
+}
+
+#[test]
+fn bitwise() {
+    let res = parse_eval("[1 << 4, -16 >> 2, 6 ^ 3, ~5, 1 + 1 << 2, 1 << 65]");
+    assert_eq!(
+        res,
+        N::Array(
+            [16.0, -4.0, 5.0, -6.0, 8.0, 2.0]
+                .iter()
+                .map(|x| N::Num(*x))
+                .collect()
+        )
+    );
+    // Shift amounts out of 0..64 wrap around
+    let res = parse_eval("[1 << 32, 1 << 64, 1 << -1, -8 >> 65, 1 >> 64]");
+    assert_eq!(
+        res,
+        N::Array(
+            [4294967296.0, 1.0, i64::MIN as f64, -4.0, 1.0]
+                .iter()
+                .map(|x| N::Num(*x))
+                .collect()
+        )
+    );
+    let err = try_parse_eval("1.5 << 1").unwrap_err();
+    assert_eq!(
+        err.kind,
+        RuntimeErrorKind::TypeMismatch {
+            op: Op::Shift,
+            lhs: "number",
+            rhs: "number"
+        }
+    );
+    let err = try_parse_eval("~0.5").unwrap_err();
+    assert_eq!(
+        err.kind,
+        RuntimeErrorKind::InvalidOperand {
+            op: Op::BitNot,
+            operand: "number"
+        }
+    );
+}
+
+#[test]
+fn shift_append_in_place() {
+    let code = r#"{
+    let arr = [1]
+    arr << 2
+    let push = (x) => arr << x
+    push(3)
+    let copy = [0] << 1
+    let n = 1
+    n << 3
+    [arr, copy, n << 3]
+}"#;
+    let res = parse_eval(code);
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![
+            N::Array(alloc::vec![N::Num(1.0), N::Num(2.0), N::Num(3.0)]),
+            N::Array(alloc::vec![N::Num(0.0), N::Num(1.0)]),
+            N::Num(8.0)
+        ])
+    );
+}
+
+#[test]
+fn shift_evaluation_order() {
+    let code = r#"{
+    let log = ""
+    let f = (x) => { log = log + x 1 }
+    let arr = [0]
+    let res = [f("a") << f("b"), arr << { arr = 1 2 }, arr]
+    [log, res]
+}"#;
+    let res = parse_eval(code);
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![
+            N::Str(String::from("ab")),
+            N::Array(alloc::vec![N::Num(2.0), N::Num(4.0), N::Num(1.0)])
+        ])
+    );
+}
+
+#[test]
+fn shift_append_memory() {
+    let mut ctx = Ctx::new();
+    ctx.max_mem = Some(10_000);
+    ctx.set_val("arr", N::Array(Vec::new()));
+    ctx.insert_code("while 1 arr << \"abcd\"");
+    let expr = ctx.parse_next_expr().unwrap();
+    let err = try_eval(&expr, &mut ctx).unwrap_err();
+    assert_eq!(err.kind, RuntimeErrorKind::MemoryLimitExceeded);
+    assert_eq!(ctx.mem, ctx.values[0].heap_size());
+}
+
+#[test]
+fn compare_or_equal() {
+    let res = parse_eval("[1 <= 1, 2 <= 1, 1 >= 1, 1 >= 2, 1 + 1 >= 2 & 3 <= 4]");
+    assert_eq!(
+        res,
+        N::Array(
+            [true, false, true, false, true]
+                .iter()
+                .map(|x| N::Bool(*x))
+                .collect()
+        )
+    );
+}
+
+#[test]
+fn compare_strings_arrays() {
+    let code = r#"[
+    "abc" < "abd", "b" > "abc", "ab" < "abc", "a" <= "a",
+    [1, 2] < [1, 3], [1, 2] < [1, 2, 0], [2] > [1, 9], [1, "b"] >= [1, "a"]
+]"#;
+    let res = parse_eval(code);
+    assert_eq!(res, N::Array(alloc::vec![N::Bool(true); 8]));
+    let err = try_parse_eval(r#"[1] < ["a"]"#).unwrap_err();
+    assert_eq!(
+        err.kind,
+        RuntimeErrorKind::TypeMismatch {
+            op: Op::Lesser,
+            lhs: "array",
+            rhs: "array"
+        }
+    );
+}
+
+#[test]
+fn equals_deep() {
+    let code = r#"[
+    [1, [2, "a"]] == [1, [2, "a"]], [1] == [1, 2], [1] != ["1"],
+    {a: [1]} == {a: [1]}, {a: 1} != {a: 2}
+]"#;
+    let res = parse_eval(code);
+    assert_eq!(
+        res,
+        N::Array(
+            [true, false, true, true, true]
+                .iter()
+                .map(|x| N::Bool(*x))
+                .collect()
+        )
+    );
+}
+
+#[test]
+fn bool_literals() {
+    let code = r#"{
+    let t = true
+    [t, !t, false == false, 1 < 2, if true "yes" else "no", if 1 "coerced" else "no", `${t}`]
+}"#;
+    let res = parse_eval(code);
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![
+            N::Bool(true),
+            N::Bool(false),
+            N::Bool(true),
+            N::Bool(true),
+            N::Str(String::from("yes")),
+            N::Str(String::from("coerced")),
+            N::Str(String::from("true"))
+        ])
+    );
+    let err = try_parse_eval("true + 1").unwrap_err();
+    assert_eq!(
+        err.kind,
+        RuntimeErrorKind::TypeMismatch {
+            op: Op::Plus,
+            lhs: "bool",
+            rhs: "number"
+        }
+    );
+}
+
+#[test]
+fn unit_literal() {
+    let code = r#"{
+    let x = null
+    let o = {a: unit}
+    [x == unit, x == null, o.a == null, o.b == null, 0 == null, [] != unit]
+}"#;
+    let res = try_parse_eval(code).unwrap();
+    assert_eq!(
+        res,
+        N::Array(
+            [true, true, true, true, false, true]
+                .iter()
+                .map(|x| N::Bool(*x))
+                .collect()
+        )
+    );
+    // An intentional unit is not a missing variable
+    assert!(try_parse_eval("{ let x = unit x }").is_ok());
+    assert!(try_parse_eval("{ x }").is_err());
+}
+
+#[test]
+fn keyword_literal_binding() {
+    for code in [
+        "let true = 1",
+        "{ let  null = 1 }",
+        "let unit = 1",
+        "let false = 1",
+    ] {
+        let mut ctx = Ctx::new();
+        ctx.insert_code(code);
+        let err = ctx.parse_next_expr().unwrap_err();
+        assert_eq!(err.msg, "cannot bind a variable to a keyword literal");
+        assert_eq!(err.expected, "identifier");
+    }
+    // Only whole names are keywords
+    assert_eq!(parse_eval("{ let trueish = 1 trueish }"), N::Num(1.0));
+    // Neither are function arguments nor loop variables
+    for code in ["(true) => 1", "for null in 3 1"] {
+        let err = try_parse_eval(code).unwrap_err();
+        assert!(matches!(err.kind, RuntimeErrorKind::Parse(_)));
+    }
+}
+
+#[test]
+fn return_early() {
+    let code = r#"{
+    let find = (arr, x) => {
+        let i = 0
+        while i < arr() {
+            { let e = arr(i) if e == x return i }
+            i = i + 1
+        }
+        "none"
+    }
+    let f = () => { return }
+    [find([5, 6, 7], 6), find([5], 6), f()]
+}"#;
+    let res = try_parse_eval(code).unwrap();
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![
+            N::Num(1.0),
+            N::Str(String::from("none")),
+            N::Unit
+        ])
+    );
+
+    // The variables of the left blocks are forgotten
+    for run in [try_eval, vm::try_eval] {
+        let mut ctx = Ctx::new();
+        ctx.insert_code(
+            "{ let f = () => { let a = 1 { let b = 2 try return b catch (e) => 0 } } f() }",
+        );
+        let expr = ctx.parse_next_expr().unwrap();
+        assert_eq!(run(&expr, &mut ctx).unwrap(), N::Num(2.0));
+        assert!(ctx.idents.is_empty());
+        assert!(!ctx.strict);
+    }
+
+    // At the top level, `return` ends the expression
+    assert_eq!(parse_eval("{ return 3 4 }"), N::Num(3.0));
+}
+
+#[test]
+fn break_continue() {
+    let code = r#"{
+    let i = 0
+    let odd = []
+    while 1 {
+        i = i + 1
+        // `break` takes the expression after it as its value
+        if i > 7 { break }
+        if i % 2 == 0 continue
+        odd << i
     }
+    let j = 0
+    let found = while j < 10 {
+        j = j + 1
+        try { if j * j > 20 break j } catch (e) => 0
+    }
+    [odd, found, while 0 break 1, { let k = 0 while k < 3 { k = k + 1 continue } }]
+}"#;
+    let res = try_parse_eval(code).unwrap();
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![
+            N::Array([1.0, 3.0, 5.0, 7.0].iter().map(|x| N::Num(*x)).collect()),
+            N::Num(5.0),
+            N::Unit,
+            N::Unit,
+        ])
+    );
+
+    // Nested loops, `break` leaves the innermost one
+    let code = "{
+    let n = 0 let i = 0
+    while i < 3 { i = i + 1 let j = 0 while 1 { j = j + 1 n = n + 1 if j == 2 break } }
+    n
+}";
+    assert_eq!(try_parse_eval(code).unwrap(), N::Num(6.0));
+}
+
+#[test]
+fn break_outside_loop() {
+    let err = try_parse_eval("{ let f = () => break 1 while 1 f() }").unwrap_err();
+    assert_eq!(err.kind, RuntimeErrorKind::OutsideLoop);
+    let err = try_parse_eval("continue").unwrap_err();
+    assert_eq!(err.kind, RuntimeErrorKind::OutsideLoop);
+    assert_eq!(err.span, Span { start: 0, end: 8 });
+    assert_eq!(
+        parse_eval("try { let f = () => { break } f() } catch (e) => e"),
+        N::Str(String::from("`break` or `continue` outside of a loop"))
+    );
+    // Outside of `try`, the function ends with `N::Unit`
+    assert_eq!(
+        parse_eval("{ let f = () => { break 2 3 } [f()] }"),
+        N::Array(alloc::vec![N::Unit])
+    );
+    // Caught by a `try` in the same function
+    assert_eq!(
+        try_parse_eval("try continue catch (e) => 1").unwrap(),
+        N::Num(1.0)
+    );
+    assert_eq!(
+        try_parse_eval(r#"{ let f = () => try continue catch (e) => "c" f() }"#).unwrap(),
+        N::Str(String::from("c"))
+    );
+    assert_eq!(
+        try_parse_eval(
+            "{ let n = 0 while n < 3 { n = n + 1 try { if n == 2 break 7 } catch (e) => 0 } }"
+        )
+        .unwrap(),
+        N::Num(7.0)
+    );
+    assert_eq!(
+        try_parse_eval("for x in [1] try { let f = () => { break } f() } catch (e) => x + 1")
+            .unwrap(),
+        N::Num(2.0)
+    );
+    // Not a keyword when followed by more letters
+    assert_eq!(
+        parse_eval("{ let breaks = 1 let return_ = 2 breaks + return_ }"),
+        N::Num(3.0)
+    );
+}
+
+#[test]
+fn for_loops() {
+    let code = r#"{
+    let sum = 0
+    for x in [1, 2, 3] sum = sum + x
+    let chars = []
+    for (c, i) in "héllo" { chars << c + i }
+    let keys = ""
+    let values = 0
+    for (v, k) in {b: 2, a: 1} { keys = keys + k values = values + v }
+    let count = []
+    for i in 4 count << i
+    [sum, chars, keys, values, count, for x in [1, 2] x * 10, for x in [] 1]
+}"#;
+    let res = try_parse_eval(code).unwrap();
+    let nums = |v: &[f64]| N::Array(v.iter().map(|x| N::Num(*x)).collect());
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![
+            N::Num(6.0),
+            N::Array(
+                ["h0", "é1", "l2", "l3", "o4"]
+                    .iter()
+                    .map(|x| N::Str(String::from(*x)))
+                    .collect()
+            ),
+            N::Str(String::from("ab")),
+            N::Num(3.0),
+            nums(&[0.0, 1.0, 2.0, 3.0]),
+            N::Num(20.0),
+            N::Unit,
+        ])
+    );
+}
+
+#[test]
+fn for_loops_control_flow() {
+    let code = "{
+    let first = (arr) => { for (x, i) in arr if x > 2 return i }
+    let odd = []
+    for x in 10 {
+        if x > 6 { break }
+        if x % 2 == 0 continue
+        odd << x
+    }
+    let total = 0
+    for row in [[1, 2], [3, 4]] for x in row { if x == 2 continue total = total + x }
+    [first([1, 5, 3]), odd, total, for x in [1, 2, 3] if x == 2 break x * 100]
+}";
+    let res = try_parse_eval(code).unwrap();
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![
+            N::Num(1.0),
+            N::Array([1.0, 3.0, 5.0].iter().map(|x| N::Num(*x)).collect()),
+            N::Num(8.0),
+            N::Num(200.0),
+        ])
+    );
+
+    // The loop variables are forgotten, even on early exit
+    for run in [try_eval, vm::try_eval] {
+        let mut ctx = Ctx::new();
+        ctx.insert_code("{ let f = () => { for (x, i) in [1, 2] { let y = x return y } } f() }");
+        let expr = ctx.parse_next_expr().unwrap();
+        assert_eq!(run(&expr, &mut ctx).unwrap(), N::Num(1.0));
+        assert!(ctx.idents.is_empty());
+        ctx.insert_code("for x in [1, 2] { let y = x }");
+        let expr = ctx.parse_next_expr().unwrap();
+        assert_eq!(run(&expr, &mut ctx).unwrap(), N::Unit);
+        assert!(ctx.idents.is_empty());
+    }
+}
+
+#[test]
+fn for_loops_closures() {
+    let code = "{
+    let fs = []
+    for x in 3 fs << () => x
+    let g = (arr) => { let k = 10 let res = [] for x in arr res << move () => x + k res }
+    [fs((f) => f()), g([1, 2])((f) => f())]
+}";
+    let res = try_parse_eval(code).unwrap();
+    let nums = |v: &[f64]| N::Array(v.iter().map(|x| N::Num(*x)).collect());
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![nums(&[0.0, 1.0, 2.0]), nums(&[11.0, 12.0])])
+    );
+}
+
+#[test]
+fn for_not_iterable() {
+    let err = try_parse_eval("{ let f = () => true for x in f() x }").unwrap_err();
+    assert_eq!(err.kind, RuntimeErrorKind::NotIterable("bool"));
+    assert_eq!(err.span, Span { start: 30, end: 33 });
+    assert_eq!(parse_eval("for x in unit x"), N::Unit);
+    let mut ctx = Ctx::new();
+    ctx.insert_code("for x [1] x");
+    let err = ctx.parse_next_expr().unwrap_err();
+    assert_eq!(err.expected, "`in`");
+}
+
+#[test]
+fn ranges() {
+    let code = "{
+    let collect = (r) => { let res = [] for x in r res << x res }
+    let n = 4
+    let step = 10
+    [
+        collect(0..n),
+        collect(1..=n),
+        collect(0..n * 2 step 3),
+        collect(n..0 step -1),
+        collect(..3),
+        collect(0.5..2),
+        collect(0..0),
+        collect(0..5 step 0),
+        step,
+    ]
+}";
+    let res = try_parse_eval(code).unwrap();
+    let nums = |v: &[f64]| N::Array(v.iter().map(|x| N::Num(*x)).collect());
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![
+            nums(&[0.0, 1.0, 2.0, 3.0]),
+            nums(&[1.0, 2.0, 3.0, 4.0]),
+            nums(&[0.0, 3.0, 6.0]),
+            nums(&[4.0, 3.0, 2.0, 1.0]),
+            nums(&[0.0, 1.0, 2.0]),
+            nums(&[0.5, 1.5]),
+            nums(&[]),
+            nums(&[]),
+            N::Num(10.0),
+        ])
+    );
+
+    // Lazy, an open range never ends by itself
+    // The end of `1..` would be the block, braces close it
+    let code = "{ let last = 0 for i in {1..} { if i * i > 50 { break } last = i } last }";
+    assert_eq!(try_parse_eval(code).unwrap(), N::Num(7.0));
+
+    let code = "[0..3 == 0..3, 0..3 != 0..=3, 1..2 + 3, `${0..=2 step 0.5}`]";
+    let res = try_parse_eval(code).unwrap();
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![
+            N::Bool(true),
+            N::Bool(true),
+            N::Range {
+                start: 1.0,
+                end: 5.0,
+                step: 1.0,
+                inclusive: false
+            },
+            N::Str(String::from("0..=2 step 0.5")),
+        ])
+    );
+
+    let err = try_parse_eval("0..true").unwrap_err();
+    assert_eq!(
+        err.kind,
+        RuntimeErrorKind::TypeMismatch {
+            op: Op::Range,
+            lhs: "number",
+            rhs: "bool"
+        }
+    );
+}
+
+#[test]
+fn slicing() {
+    let code = r#"{
+    let arr = [0, 1, 2, 3, 4]
+    let s = "héllo"
+    [
+        arr(1..3),
+        arr(-2..),
+        arr(..=1),
+        arr(0..-1 step 2),
+        arr(3..10),
+        arr(4..0 step -2),
+        arr(10..),
+        s(-3..),
+        s(1..=2),
+        s(..),
+    ]
+}"#;
+    let res = try_parse_eval(code).unwrap();
+    let nums = |v: &[f64]| N::Array(v.iter().map(|x| N::Num(*x)).collect());
+    let st = |s: &str| N::Str(String::from(s));
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![
+            nums(&[1.0, 2.0]),
+            nums(&[3.0, 4.0]),
+            nums(&[0.0, 1.0]),
+            nums(&[0.0, 2.0]),
+            nums(&[3.0, 4.0]),
+            nums(&[4.0, 2.0]),
+            nums(&[]),
+            st("llo"),
+            st("él"),
+            st("héllo"),
+        ])
+    );
+    let err = try_parse_eval(r#""abc"(1)"#).unwrap_err();
+    assert_eq!(
+        err.kind,
+        RuntimeErrorKind::InvalidIndex {
+            target: "string",
+            index: "number"
+        }
+    );
+}
+
+#[test]
+fn open_range_step() {
+    let code = "{
+    let step = 2
+    let r = []
+    for i in {5.. step -2} { if i < 0 { break } r << i }
+    [
+        [1, 2, 3](0.. step -1),
+        [1, 2, 3](-1.. step -1),
+        [0, 1, 2, 3, 4](1.. step 2),
+        [0, 1, 2, 3](0..{step}),
+        r,
+    ]
+}";
+    assert_eq!(
+        try_parse_eval(code).unwrap(),
+        parse_eval("[[1], [3, 2, 1], [1, 3], [0, 1], [5, 3, 1]]")
+    );
+}
+
+#[test]
+fn slicing_fractional_step() {
+    for (code, target) in [
+        ("[1, 2, 3](0..3 step 0.00001)", "array"),
+        (r#""abc"(0..3 step 0.5)"#, "string"),
+    ] {
+        let err = try_parse_eval(code).unwrap_err();
+        assert_eq!(
+            err.kind,
+            RuntimeErrorKind::InvalidIndex {
+                target,
+                index: "range with a fractional step"
+            }
+        );
+    }
+    assert_eq!(parse_eval("[1, 2, 3](0..3 step 1.5)"), N::Unit);
+}
+
+#[test]
+fn slicing_memory_limit() {
+    // The collections fit, not their copies
+    for code in [
+        "{ let a = [] for i in 300 a << i a(..) }",
+        r#"{ let s = "a" for i in 13 s = s + s s(..) }"#,
+    ] {
+        let mut ctx = Ctx::new();
+        ctx.max_mem = Some(15_000);
+        ctx.insert_code(code);
+        let expr = ctx.parse_next_expr().unwrap();
+        let err = try_eval(&expr, &mut ctx).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::MemoryLimitExceeded);
+        assert_eq!(ctx.mem, 0);
+        let err = vm::try_eval(&expr, &mut ctx).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::MemoryLimitExceeded);
+        assert_eq!(ctx.mem, 0);
+    }
+}
+
+#[test]
+fn match_literals() {
+    let code = r#"{
+    let describe = (x) => match x {
+        // The comma keeps `-1` from being a subtraction
+        0 => "zero",
+        -1 => "minus one"
+        "a" => "letter a"
+        true => "yes"
+        unit => "nothing"
+        n if n > 100 => `big ${n}`
+        _ => "other"
+    }
+    [describe(0), describe(-1), describe("a"), describe(true), describe(unit), describe(101), describe(5), match 1 { 2 => 3 }]
+}"#;
+    let res = try_parse_eval(code).unwrap();
+    let st = |s: &str| N::Str(String::from(s));
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![
+            st("zero"),
+            st("minus one"),
+            st("letter a"),
+            st("yes"),
+            st("nothing"),
+            st("big 101"),
+            st("other"),
+            N::Unit,
+        ])
+    );
+}
+
+#[test]
+fn match_arrays() {
+    let code = "{
+    let sum = (arr) => match arr { [] => 0, [head, ..tail] => head + sum(tail) }
+    let ends = (arr) => match arr {
+        [x] => [x]
+        [first, .., last] => [first, last]
+        _ => []
+    }
+    let init = match [1, 2, 3] { [..rest, 3] => rest }
+    [sum([1, 2, 3, 4]), ends([7]), ends([1, 2, 3]), ends([]), init, match [1, 2] { [a] => a, [a, b, c] => c }]
+}";
+    let res = try_parse_eval(code).unwrap();
+    let nums = |v: &[f64]| N::Array(v.iter().map(|x| N::Num(*x)).collect());
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![
+            N::Num(10.0),
+            nums(&[7.0]),
+            nums(&[1.0, 3.0]),
+            nums(&[]),
+            nums(&[1.0, 2.0]),
+            N::Unit,
+        ])
+    );
+}
+
+#[test]
+fn match_objects() {
+    let code = r#"{
+    let area = (shape) => match shape {
+        {kind: "square", size} => size * size
+        {kind: "rect", size: [w, h]} => w * h
+        {"kind": k} => `unknown ${k}`
+        _ => 0
+    }
+    [area({kind: "square", size: 3}), area({kind: "rect", size: [2, 5], color: 1}), area({kind: "dot"}), area({size: 2})]
+}"#;
+    let res = try_parse_eval(code).unwrap();
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![
+            N::Num(9.0),
+            N::Num(10.0),
+            N::Str(String::from("unknown dot")),
+            N::Num(0.0),
+        ])
+    );
+}
+
+#[test]
+fn match_scope() {
+    // A failed arm forgets its bindings, the matching arm forgets them after its body
+    for run in [try_eval, vm::try_eval] {
+        let mut ctx = Ctx::new();
+        ctx.insert_code(
+            "{ let x = 1 let f = match [2, 3] { [x, 0] => 0, [a, b] if a > 5 => 0, [a, b] => () => a + b + x } f() }",
+        );
+        let expr = ctx.parse_next_expr().unwrap();
+        assert_eq!(run(&expr, &mut ctx).unwrap(), N::Num(6.0));
+        assert!(ctx.idents.is_empty());
+    }
+
+    let code = "{ let find = (arr) => { for x in arr match x { [y] if y > 1 => return y, _ => continue } } find([[1], 2, [3], [4]]) }";
+    assert_eq!(try_parse_eval(code).unwrap(), N::Num(3.0));
+}
+
+#[test]
+fn match_parse_errors() {
+    for (code, expected) in [
+        ("match 1 { 1 2 }", "`=>`"),
+        ("match 1 { [..a, ..b] => 0 }", "pattern"),
+        ("match 1 { 1 + 1 => 0 }", "`=>`"),
+        (r#"match 1 { {"a"} => 0 }"#, "`:`"),
+        ("match 1 1 => 0", "`{`"),
+    ] {
+        let mut ctx = Ctx::new();
+        ctx.insert_code(code);
+        let err = ctx.parse_next_expr().unwrap_err();
+        assert_eq!(err.expected, expected, "{}", code);
+    }
+}
+
+#[test]
+fn let_destructuring() {
+    let code = r#"{
+    let [a, b, ..rest] = [1, 2, 3, 4]
+    let {x, y: [y1, y2], "with space": z} = {x: 5, y: [6, 7], "with space": 8, other: 0}
+    let [first, .., last] = [10, 20, 30]
+    let [_, second] = [1, 2]
+    [a, b, rest, x, y1, y2, z, first + last, second]
+}"#;
+    let res = try_parse_eval(code).unwrap();
+    let nums = |v: &[f64]| N::Array(v.iter().map(|x| N::Num(*x)).collect());
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![
+            N::Num(1.0),
+            N::Num(2.0),
+            nums(&[3.0, 4.0]),
+            N::Num(5.0),
+            N::Num(6.0),
+            N::Num(7.0),
+            N::Num(8.0),
+            N::Num(40.0),
+            N::Num(2.0),
+        ])
+    );
+}
+
+#[test]
+fn let_destructuring_native() {
+    for run in [eval, vm::eval] {
+        let mut ctx = Ctx::new();
+        let div_rem = Rc::new(|a: N, b: N, _, _| {
+            let (a, b) = (a.as_f64(), b.as_f64());
+            N::Array(alloc::vec![N::Num((a / b) as i64 as f64), N::Num(a % b)])
+        });
+        ctx.set_val("div_rem", N::FuncNativeDef(Native(div_rem)));
+        ctx.insert_code("{ let [q, r] = div_rem(17, 5) q * 10 + r }");
+        let expr = ctx.parse_next_expr().unwrap();
+        assert_eq!(run(&expr, &mut ctx).unwrap(), N::Num(32.0));
+        assert_eq!(ctx.idents, ["div_rem"]);
+    }
+}
+
+#[test]
+fn args_destructuring() {
+    let code = "{
+    let dist = ({x, y}, [dx, dy]) => { let res = {x + dx} * {y + dy} res }
+    let swap = move ([a, b]) => [b, a]
+    let k = 100
+    let add_k = [[1, 2], [3, 4]](([a, b]) => a + b + k)
+    [dist({x: 1, y: 2}, [1, 1]), swap([1, 2]), add_k]
+}";
+    let res = try_parse_eval(code).unwrap();
+    let nums = |v: &[f64]| N::Array(v.iter().map(|x| N::Num(*x)).collect());
+    assert_eq!(
+        res,
+        N::Array(alloc::vec![
+            N::Num(6.0),
+            nums(&[2.0, 1.0]),
+            nums(&[103.0, 107.0]),
+        ])
+    );
+}
+
+#[test]
+fn destructuring_mismatch() {
+    let err = try_parse_eval("{ let [a, b] = [1] a }").unwrap_err();
+    assert_eq!(err.kind, RuntimeErrorKind::PatternMismatch("array"));
+    assert_eq!(err.span, Span { start: 2, end: 18 });
+    let err = try_parse_eval("{ let f = ({x}) => x f(1) }").unwrap_err();
+    assert_eq!(err.kind, RuntimeErrorKind::PatternMismatch("number"));
+    assert_eq!(err.span, Span { start: 11, end: 14 });
+    // Without `try`, all the variables are unit
+    let code = "{ let [a, [b, c]] = [1, [2]] let d = 4 [a, b, c, d] }";
+    assert_eq!(
+        parse_eval(code),
+        N::Array(alloc::vec![N::Unit, N::Unit, N::Unit, N::Num(4.0)])
+    );
+    assert_eq!(
+        parse_eval("try { let {a} = {b: 1} } catch (e) => e"),
+        N::Str(String::from("object doesn't match the pattern"))
+    );
+    let mut ctx = Ctx::new();
+    ctx.insert_code("let [a, b] 1");
+    assert_eq!(ctx.parse_next_expr().unwrap_err().expected, "`=`");
 }
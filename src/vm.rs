@@ -0,0 +1,1197 @@
+//! Bytecode backend, an alternative to the tree-walking [eval](crate::eval).
+//!
+//! The AST is compiled to a flat list of instructions run by a stack machine.
+//! Variables still live in the `Ctx`, and operators, calls of natives and arrays,
+//! errors and limits share their implementation with the tree-walker: both backends
+//! give the same results. Nodes without a dedicated instruction, like closure creations,
+//! run no script code and are handed to the tree-walker.
+//!
+//! Script function calls don't use the native stack, map, filter and reduce call
+//! their function one element at a time: running out of fuel between two instructions,
+//! even in such a callback, suspends the `Vm` instead of losing its progress.
+
+use crate::*;
+use alloc::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy)]
+enum Instr {
+    /// Push a constant
+    Const(usize),
+    /// Push the value of a variable
+    Get(usize),
+    /// Pop a value and declare a variable with it, push `N::Unit`
+    Set(usize),
+    /// Pop a value and assign it to a declared variable, push `N::Unit`
+    Assign(usize),
+    /// Push the variable at a slot of the function frame
+    GetLocal(usize),
+    /// Pop a value and assign it to a slot of the function frame, push `N::Unit`
+    SetLocal(usize),
+    /// Push the value of a constant `N::Cell`
+    GetCell(usize),
+    /// Pop a value and store it in a constant `N::Cell`, push `N::Unit`
+    SetCell(usize),
+    Pop,
+    Swap,
+    Binary(Op),
+    Unary(Op),
+    Jump(usize),
+    /// Pop the condition, jump if false
+    JumpIfFalse(usize),
+    /// Jump if the constant variable node holds an array, `<<` appends to it
+    JumpIfArray(usize, usize),
+    /// Pop a value and append it to the array of the constant variable node, push `N::Unit`
+    Append(usize),
+    /// Pop the keys and a value, and store it at the keys path in the constant variable node, push `N::Unit`
+    SetElement(usize, usize),
+    /// Replace the left side of a logical operator by the result and jump, when it decides alone
+    ShortCircuit(Op, usize),
+    /// Jump backward, to the start of a loop
+    Loop(usize),
+    /// Enter a loop, `break` jumps to the first address and `continue` to the second
+    LoopStart(usize, usize),
+    /// Pop a value and enter a `for` loop over it, like `LoopStart`
+    For(usize, usize),
+    /// Push the index (or key) and the next element of the `for` loop, jump when there is none
+    Next(usize),
+    /// Leave a loop
+    LoopEnd,
+    /// Remember the variables declared before a block
+    Enter,
+    /// Forget the variables declared in the block
+    Leave,
+    /// Pop the arguments and the function, push the result
+    Call(usize),
+    /// Pop the elements, push the array
+    Array(usize),
+    /// Pop the values of the fields of a constant object literal, push the object
+    Object(usize),
+    /// Pop the parts of a template string, push them joined
+    Concat(usize),
+    /// Pop a value and declare the variables of a pattern with it, push `N::Unit`
+    Destructure(usize),
+    /// Match the value on top of the stack against a pattern, declaring its variables, jump if it doesn't match
+    Bind(usize, usize),
+    Throw,
+    /// Pop a value and return it from the function
+    Exit,
+    /// Pop a value and leave the loop with it
+    Break,
+    Continue,
+    /// Catch the errors until `EndTry`, the handler starts at the address
+    Try(usize),
+    EndTry,
+    /// Evaluate a constant node running no script code with the tree-walker, like a closure creation
+    Eval(usize),
+    Return,
+}
+
+/// Compiled code of an expression or a function body
+#[derive(Debug, Default)]
+pub struct Chunk {
+    code: Vec<Instr>,
+    /// Location of each instruction in the code, for errors
+    spans: Vec<Span>,
+    consts: Vec<N>,
+    names: Vec<ID>,
+    patterns: Vec<Pattern>,
+}
+
+impl Chunk {
+    /// Compile the expression `n`
+    pub fn compile(n: &N) -> Chunk {
+        let mut chunk = Chunk::default();
+        chunk.expr(n);
+        chunk.emit(Instr::Return, n.span());
+        chunk
+    }
+
+    fn emit(&mut self, instr: Instr, span: Span) -> usize {
+        self.code.push(instr);
+        self.spans.push(span);
+        self.code.len() - 1
+    }
+
+    /// Point the jump at `at` to the next instruction
+    fn patch(&mut self, at: usize) {
+        let to = self.code.len();
+        match &mut self.code[at] {
+            Instr::Jump(target)
+            | Instr::JumpIfFalse(target)
+            | Instr::ShortCircuit(_, target)
+            | Instr::Next(target)
+            | Instr::Bind(_, target)
+            | Instr::JumpIfArray(_, target)
+            | Instr::Try(target) => *target = to,
+            _ => unreachable!(),
+        }
+    }
+
+    fn constant(&mut self, n: N) -> usize {
+        self.consts.push(n);
+        self.consts.len() - 1
+    }
+
+    fn name(&mut self, name: &str) -> usize {
+        match self.names.iter().position(|e| e == name) {
+            Some(i) => i,
+            None => {
+                self.names.push(String::from(name));
+                self.names.len() - 1
+            }
+        }
+    }
+
+    /// Compile `n`, leaving exactly one value on the stack
+    fn expr(&mut self, n: &N) {
+        let span = n.span();
+        match n {
+            N::Num(_) | N::Str(_) | N::Bool(_) | N::Unit | N::FuncNativeDef(_) => {
+                let i = self.constant(n.clone());
+                self.emit(Instr::Const(i), span);
+            }
+            N::Get(name, _) => {
+                let i = self.name(name);
+                self.emit(Instr::Get(i), span);
+            }
+            N::Local { depth: 0, slot } => {
+                self.emit(Instr::GetLocal(*slot), span);
+            }
+            N::Cell(_) => {
+                let i = self.constant(n.clone());
+                self.emit(Instr::GetCell(i), span);
+            }
+            N::Set(name, val) => {
+                self.expr(val);
+                let i = self.name(name);
+                self.emit(Instr::Set(i), span);
+            }
+            N::Binary(Op::Assign, l, r, _) if matches!(l.as_ref(), N::Get(..)) => {
+                let N::Get(name, span) = l.as_ref() else {
+                    unreachable!()
+                };
+                self.expr(r);
+                let i = self.name(name);
+                self.emit(Instr::Assign(i), *span);
+            }
+            N::Binary(Op::Assign, l, r, _) if matches!(l.as_ref(), N::Local { depth: 0, .. }) => {
+                let N::Local { slot, .. } = l.as_ref() else {
+                    unreachable!()
+                };
+                self.expr(r);
+                self.emit(Instr::SetLocal(*slot), span);
+            }
+            N::Binary(Op::Assign, l, r, _) if matches!(l.as_ref(), N::Cell(_)) => {
+                self.expr(r);
+                let i = self.constant(l.as_ref().clone());
+                self.emit(Instr::SetCell(i), span);
+            }
+            N::Unary(op, val, _) => {
+                self.expr(val);
+                self.emit(Instr::Unary(*op), span);
+            }
+            N::Binary(Op::Assign, l, r, _) if matches!(l.as_ref(), N::FuncCall { .. }) => {
+                self.expr(r);
+                // The keys from the last one, like the tree-walker
+                let mut keys = 0;
+                let mut var = l.as_ref();
+                while let N::FuncCall { func, args, .. } = var {
+                    let [key] = args.as_slice() else {
+                        // Not an element, the assignment fails
+                        var = &N::Unit;
+                        break;
+                    };
+                    self.expr(key);
+                    keys += 1;
+                    var = func;
+                }
+                let i = self.constant(var.clone());
+                self.emit(Instr::SetElement(i, keys), span);
+            }
+            // Appends in place to an array variable
+            N::Binary(Op::Shift, l, r, _)
+                if matches!(
+                    l.as_ref(),
+                    N::Get(..) | N::Local { depth: 0, .. } | N::Cell(_)
+                ) =>
+            {
+                let i = self.constant(l.as_ref().clone());
+                let to_append = self.emit(Instr::JumpIfArray(i, 0), span);
+                self.expr(l);
+                self.expr(r);
+                self.emit(Instr::Binary(Op::Shift), span);
+                let to_end = self.emit(Instr::Jump(0), span);
+                self.patch(to_append);
+                self.expr(r);
+                self.emit(Instr::Append(i), span);
+                self.patch(to_end);
+            }
+            N::Binary(op @ (Op::And | Op::Or | Op::And2 | Op::Or2), l, r, _) => {
+                self.expr(l);
+                let to_end = self.emit(Instr::ShortCircuit(*op, 0), span);
+                self.expr(r);
+                self.emit(Instr::Binary(*op), span);
+                self.patch(to_end);
+            }
+            N::Binary(op, l, r, _) if *op != Op::Assign => {
+                self.expr(l);
+                self.expr(r);
+                self.emit(Instr::Binary(*op), span);
+            }
+            N::If {
+                condition,
+                path_true,
+                path_false,
+            } => {
+                self.expr(condition);
+                let to_false = self.emit(Instr::JumpIfFalse(0), span);
+                self.expr(path_true);
+                let to_end = self.emit(Instr::Jump(0), span);
+                self.patch(to_false);
+                self.expr(path_false);
+                self.patch(to_end);
+            }
+            N::While { condition, body } => {
+                let loop_start = self.emit(Instr::LoopStart(0, 0), span);
+                // The last body value stays on the stack, under the condition
+                let i = self.constant(N::Unit);
+                self.emit(Instr::Const(i), span);
+                let start = self.code.len();
+                self.expr(condition);
+                let to_end = self.emit(Instr::JumpIfFalse(0), span);
+                self.emit(Instr::Pop, span);
+                self.expr(body);
+                let cont = self.emit(Instr::Loop(start), condition.span());
+                self.patch(to_end);
+                self.emit(Instr::LoopEnd, span);
+                self.code[loop_start] = Instr::LoopStart(self.code.len(), cont);
+            }
+            N::For { vars, iter, body } => {
+                let span = iter.span();
+                self.expr(iter);
+                let for_start = self.emit(Instr::For(0, 0), span);
+                // The last body value is replaced by the element and its index
+                let next = self.emit(Instr::Next(0), span);
+                self.emit(Instr::Enter, span);
+                for name in vars {
+                    let i = self.name(name);
+                    self.emit(Instr::Set(i), span);
+                    self.emit(Instr::Pop, span);
+                }
+                if vars.len() < 2 {
+                    self.emit(Instr::Pop, span);
+                }
+                self.expr(body);
+                self.emit(Instr::Leave, span);
+                self.emit(Instr::Loop(next), span);
+                self.patch(next);
+                self.emit(Instr::LoopEnd, span);
+                self.code[for_start] = Instr::For(self.code.len(), next);
+            }
+            N::Destructure(pattern, val, _) => {
+                self.expr(val);
+                self.patterns.push(pattern.as_ref().clone());
+                self.emit(Instr::Destructure(self.patterns.len() - 1), span);
+            }
+            N::Match { value, arms } => {
+                let span = value.span();
+                // The value stays on the stack while the arms are tried
+                self.expr(value);
+                let mut to_end = Vec::new();
+                for arm in arms {
+                    self.emit(Instr::Enter, span);
+                    self.patterns.push(arm.pattern.clone());
+                    let mut to_next =
+                        alloc::vec![self.emit(Instr::Bind(self.patterns.len() - 1, 0), span)];
+                    if let Some(guard) = &arm.guard {
+                        self.expr(guard);
+                        to_next.push(self.emit(Instr::JumpIfFalse(0), span));
+                    }
+                    self.expr(&arm.body);
+                    self.emit(Instr::Leave, span);
+                    self.emit(Instr::Swap, span);
+                    self.emit(Instr::Pop, span);
+                    to_end.push(self.emit(Instr::Jump(0), span));
+                    for at in to_next {
+                        self.patch(at);
+                    }
+                    self.emit(Instr::Leave, span);
+                }
+                // No arm matched
+                self.emit(Instr::Pop, span);
+                let i = self.constant(N::Unit);
+                self.emit(Instr::Const(i), span);
+                for at in to_end {
+                    self.patch(at);
+                }
+            }
+            N::Block(arr) => {
+                self.emit(Instr::Enter, span);
+                if arr.is_empty() {
+                    let i = self.constant(N::Unit);
+                    self.emit(Instr::Const(i), span);
+                }
+                for (index, e) in arr.iter().enumerate() {
+                    if index > 0 {
+                        self.emit(Instr::Pop, span);
+                    }
+                    self.expr(e);
+                }
+                self.emit(Instr::Leave, span);
+            }
+            N::FuncCall { func, args, span } => {
+                self.expr(func);
+                for arg in args {
+                    self.expr(arg);
+                }
+                self.emit(Instr::Call(args.len()), *span);
+            }
+            N::Array(v) => {
+                for e in v {
+                    self.expr(e);
+                }
+                self.emit(Instr::Array(v.len()), span);
+            }
+            N::Object(map) => {
+                for e in map.values() {
+                    self.expr(e);
+                }
+                let i = self.constant(n.clone());
+                self.emit(Instr::Object(i), span);
+            }
+            N::Concat(parts, _) => {
+                for e in parts {
+                    self.expr(e);
+                }
+                self.emit(Instr::Concat(parts.len()), span);
+            }
+            N::Throw(val, _) => {
+                self.expr(val);
+                self.emit(Instr::Throw, span);
+            }
+            N::Return(val, _) => {
+                self.expr(val);
+                self.emit(Instr::Exit, span);
+            }
+            N::Break(val, _) => {
+                self.expr(val);
+                self.emit(Instr::Break, span);
+            }
+            N::Continue(_) => {
+                self.emit(Instr::Continue, span);
+            }
+            N::Try { body, catch } => {
+                let to_handler = self.emit(Instr::Try(0), span);
+                self.expr(body);
+                self.emit(Instr::EndTry, span);
+                let to_end = self.emit(Instr::Jump(0), span);
+                // The error is on the stack, call the handler with it
+                self.patch(to_handler);
+                self.expr(catch);
+                self.emit(Instr::Swap, span);
+                self.emit(Instr::Call(1), span);
+                self.patch(to_end);
+            }
+            n => {
+                let i = self.constant(n.clone());
+                self.emit(Instr::Eval(i), span);
+            }
+        }
+    }
+}
+
+struct Frame {
+    chunk: Rc<Chunk>,
+    ip: usize,
+    /// Variables to forget on return, `None` for the top level expression
+    /// whose declarations stay in the `Ctx`
+    base: Option<usize>,
+    /// `Ctx::frame` of the caller
+    caller: usize,
+    /// Lengths of the `Vm` stacks at the call, restored by `return`
+    stack: usize,
+    marks: usize,
+    handlers: usize,
+    loops: usize,
+    /// Called by the last `Walk`, the result goes to it
+    walk: bool,
+}
+
+/// Map, filter or reduce of a collection by a script function, one call at a time
+struct Walk {
+    kind: WalkKind,
+    f: N,
+    /// Elements, with their index or key
+    items: Vec<(N, N)>,
+    next: usize,
+    /// Results, or kept elements
+    res: Vec<N>,
+    /// Accumulator of a reduce
+    acc: N,
+    span: Span,
+}
+
+#[derive(Clone, Copy)]
+enum WalkKind {
+    Map,
+    MapObject,
+    Filter,
+    Reduce,
+}
+
+/// State to restore when an error is caught
+struct Handler {
+    ip: usize,
+    frames: usize,
+    stack: usize,
+    marks: usize,
+    values: usize,
+    deep: usize,
+    strict: bool,
+    frame: usize,
+    loops: usize,
+    walks: usize,
+}
+
+/// Loop being run, restored by `break` and `continue`
+struct LoopRec {
+    brk: usize,
+    cont: usize,
+    frames: usize,
+    stack: usize,
+    marks: usize,
+    handlers: usize,
+    /// Where a `for` loop is in the value it iterates
+    cursor: Option<Cursor>,
+}
+
+/// State of the `Ctx` owned by a suspended `Vm`
+struct Suspended {
+    deep: usize,
+    strict: bool,
+    frame: usize,
+}
+
+/// Stack machine running a compiled expression.
+///
+/// A `Vm` can be run many times, the expression is compiled once.
+pub struct Vm {
+    chunk: Rc<Chunk>,
+    frames: Vec<Frame>,
+    stack: Vec<N>,
+    /// Number of variables before each block being evaluated
+    marks: Vec<usize>,
+    handlers: Vec<Handler>,
+    loops: Vec<LoopRec>,
+    walks: Vec<Walk>,
+    /// Compiled function bodies, by address
+    bodies: BTreeMap<*const N, (Rc<N>, Rc<Chunk>)>,
+    suspended: Option<Suspended>,
+    /// Span of the expression, given to `OutOfFuel`
+    span: Span,
+    /// `Ctx` state before the run
+    values: usize,
+    deep: usize,
+    strict: bool,
+    frame: usize,
+}
+
+impl Vm {
+    pub fn new(n: &N) -> Vm {
+        Vm {
+            chunk: Rc::new(Chunk::compile(n)),
+            frames: Vec::new(),
+            stack: Vec::new(),
+            marks: Vec::new(),
+            handlers: Vec::new(),
+            loops: Vec::new(),
+            walks: Vec::new(),
+            bodies: BTreeMap::new(),
+            suspended: None,
+            span: n.span(),
+            values: 0,
+            deep: 0,
+            strict: false,
+            frame: 0,
+        }
+    }
+
+    /// Run the expression like [eval](crate::eval), runtime errors evaluate to `N::Unit`.
+    /// On an uncaught `throw` or a limit, the `Vm` stops and the error is returned.
+    pub fn eval(&mut self, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+        self.run(ctx, false).inspect_err(|_| self.stop(ctx))
+    }
+
+    /// Give up a suspended run, the `Ctx` is given back as it was before it
+    pub fn stop(&mut self, ctx: &mut Ctx) {
+        if self.suspended.is_some() {
+            self.resume(ctx);
+            self.abort(ctx);
+        }
+    }
+
+    /// Run the expression like [try_eval](crate::try_eval), stopping at the first runtime error.
+    ///
+    /// When `Ctx::fuel` runs out between two instructions, the `Vm` is suspended:
+    /// refuel and call `try_eval` again to continue where it stopped.
+    /// Until then, the `Ctx` holds the variables of the script, don't evaluate anything else with it.
+    pub fn try_eval(&mut self, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+        self.run(ctx, true)
+    }
+
+    fn run(&mut self, ctx: &mut Ctx, strict: bool) -> Result<N, RuntimeError> {
+        if self.suspended.is_some() {
+            self.resume(ctx);
+        } else {
+            self.values = ctx.values.len();
+            self.deep = ctx.deep;
+            self.strict = core::mem::replace(&mut ctx.strict, strict);
+            self.frame = core::mem::replace(&mut ctx.frame, ctx.values.len());
+            self.frames.push(Frame {
+                chunk: self.chunk.clone(),
+                ip: 0,
+                base: None,
+                caller: self.frame,
+                stack: 0,
+                marks: 0,
+                handlers: 0,
+                loops: 0,
+                walk: false,
+            });
+        }
+        loop {
+            if ctx.fuel == Some(0) {
+                // Stopped between two instructions, nothing is lost
+                self.suspended = Some(Suspended {
+                    deep: ctx.deep - self.deep,
+                    strict: ctx.strict,
+                    frame: ctx.frame,
+                });
+                ctx.deep = self.deep;
+                ctx.strict = self.strict;
+                ctx.frame = self.frame;
+                return Err(RuntimeError {
+                    kind: RuntimeErrorKind::OutOfFuel,
+                    span: self.span,
+                });
+            }
+            match self.step(ctx) {
+                Ok(None) => {}
+                Ok(Some(n)) => {
+                    ctx.strict = self.strict;
+                    ctx.frame = self.frame;
+                    return Ok(n);
+                }
+                Err(e) => self.catch(e, ctx)?,
+            }
+        }
+    }
+
+    /// True when stopped by `OutOfFuel`, see [Vm::try_eval]
+    pub fn is_suspended(&self) -> bool {
+        self.suspended.is_some()
+    }
+
+    fn resume(&mut self, ctx: &mut Ctx) {
+        if let Some(s) = self.suspended.take() {
+            self.deep = ctx.deep;
+            self.strict = core::mem::replace(&mut ctx.strict, s.strict);
+            self.frame = core::mem::replace(&mut ctx.frame, s.frame);
+            ctx.deep += s.deep;
+        }
+    }
+
+    /// Give the error to the innermost `try`, or stop.
+    /// `return`, `break` and `continue` jump to the end of their function or loop.
+    fn catch(&mut self, e: RuntimeError, ctx: &mut Ctx) -> Result<(), RuntimeError> {
+        let frames = self.frames.len();
+        match e.kind {
+            RuntimeErrorKind::Return(val) => {
+                self.exit(val, ctx);
+                return Ok(());
+            }
+            RuntimeErrorKind::Break(_) | RuntimeErrorKind::Continue
+                if self.loops.last().is_some_and(|l| l.frames == frames) =>
+            {
+                let l = self.loops.last().unwrap();
+                let (stack, marks, handlers, brk, cont) =
+                    (l.stack, l.marks, l.handlers, l.brk, l.cont);
+                self.unwind(stack, marks, handlers, self.loops.len(), ctx);
+                let frame = self.frames.last_mut().unwrap();
+                if let RuntimeErrorKind::Break(val) = e.kind {
+                    self.loops.pop();
+                    self.stack.push(val);
+                    frame.ip = brk;
+                } else {
+                    self.stack.push(N::Unit);
+                    frame.ip = cont;
+                }
+                return Ok(());
+            }
+            RuntimeErrorKind::Break(_) | RuntimeErrorKind::Continue => {
+                return match ctx.fail(RuntimeErrorKind::OutsideLoop, e.span) {
+                    Ok(val) => {
+                        self.exit(val, ctx);
+                        Ok(())
+                    }
+                    Err(e) => self.catch(e, ctx),
+                };
+            }
+            _ => {}
+        }
+        let handler = match self.handlers.pop() {
+            Some(handler) if e.kind.is_catchable() => handler,
+            _ => {
+                self.abort(ctx);
+                let mut e = e;
+                if e.kind == RuntimeErrorKind::OutOfFuel {
+                    // Like the tree-walker, where it ran out depends on the backend
+                    e.span = self.span;
+                }
+                return Err(e);
+            }
+        };
+        self.frames.truncate(handler.frames);
+        self.loops.truncate(handler.loops);
+        self.walks.truncate(handler.walks);
+        self.stack.truncate(handler.stack);
+        self.marks.truncate(handler.marks);
+        ctx.drain(handler.values);
+        ctx.deep = handler.deep;
+        ctx.strict = handler.strict;
+        ctx.frame = handler.frame;
+        if let Some(frame) = self.frames.last_mut() {
+            frame.ip = handler.ip;
+        }
+        self.stack.push(match e.kind {
+            RuntimeErrorKind::Thrown(val) => val,
+            kind => N::Str(format!("{}", kind)),
+        });
+        Ok(())
+    }
+
+    /// Leave the function being run with `val`, through its last `Return`
+    fn exit(&mut self, val: N, ctx: &mut Ctx) {
+        let Some(frame) = self.frames.last() else {
+            return;
+        };
+        let (stack, marks, handlers, loops) =
+            (frame.stack, frame.marks, frame.handlers, frame.loops);
+        self.unwind(stack, marks, handlers, loops, ctx);
+        self.stack.push(val);
+        if let Some(frame) = self.frames.last_mut() {
+            frame.ip = frame.chunk.code.len() - 1;
+        }
+    }
+
+    /// Forget what was started in the current frame after the given lengths
+    fn unwind(&mut self, stack: usize, marks: usize, handlers: usize, loops: usize, ctx: &mut Ctx) {
+        if let Some(handler) = self.handlers.get(handlers) {
+            ctx.strict = handler.strict;
+        }
+        self.handlers.truncate(handlers);
+        self.loops.truncate(loops);
+        if let Some(&from) = self.marks.get(marks) {
+            ctx.drain(from);
+        }
+        self.marks.truncate(marks);
+        self.stack.truncate(stack);
+    }
+
+    /// Stop the run, and give the `Ctx` back as it was
+    fn abort(&mut self, ctx: &mut Ctx) {
+        self.frames.clear();
+        self.stack.clear();
+        self.marks.clear();
+        self.handlers.clear();
+        self.loops.clear();
+        self.walks.clear();
+        ctx.drain(self.values.min(ctx.values.len()));
+        ctx.deep = self.deep;
+        ctx.strict = self.strict;
+        ctx.frame = self.frame;
+    }
+
+    /// Compiled body of a function, compiled at its first call
+    fn body(&mut self, scope: &Rc<N>) -> Rc<Chunk> {
+        self.bodies
+            .entry(Rc::as_ptr(scope))
+            .or_insert_with(|| (scope.clone(), Rc::new(Chunk::compile(scope))))
+            .1
+            .clone()
+    }
+
+    /// Push the frame of a call of a script function `f`
+    fn call(
+        &mut self,
+        f: &N,
+        args: Vec<N>,
+        walk: bool,
+        span: Span,
+        ctx: &mut Ctx,
+    ) -> Result<(), RuntimeError> {
+        let N::FuncDef {
+            args_name, scope, ..
+        } = f
+        else {
+            return Ok(());
+        };
+        if ctx.deep >= ctx.max_deep {
+            return Err(RuntimeError {
+                kind: RuntimeErrorKind::StackOverflow,
+                span,
+            });
+        }
+        ctx.deep += 1;
+        let chunk = self.body(scope);
+        let base = ctx.values.len();
+        let mut args = args.into_iter();
+        for arg_name in args_name.iter() {
+            ctx.set_val(arg_name, args.next().unwrap_or(N::Unit));
+        }
+        self.frames.push(Frame {
+            chunk,
+            ip: 0,
+            base: Some(base),
+            caller: core::mem::replace(&mut ctx.frame, base),
+            stack: self.stack.len(),
+            marks: self.marks.len(),
+            handlers: self.handlers.len(),
+            loops: self.loops.len(),
+            walk,
+        });
+        Ok(())
+    }
+
+    /// Start a map or a filter of `items` by the script function `f`
+    fn walk(
+        &mut self,
+        kind: WalkKind,
+        f: N,
+        items: Vec<(N, N)>,
+        span: Span,
+        ctx: &mut Ctx,
+    ) -> Result<(), RuntimeError> {
+        self.walks.push(Walk {
+            kind,
+            f,
+            items,
+            next: 0,
+            res: Vec::new(),
+            acc: N::Unit,
+            span,
+        });
+        self.walk_next(ctx)
+    }
+
+    /// Call the function of the last `Walk` with its next item, or push its result when it is done
+    fn walk_next(&mut self, ctx: &mut Ctx) -> Result<(), RuntimeError> {
+        let Some(w) = self.walks.last_mut() else {
+            return Ok(());
+        };
+        let span = w.span;
+        if let Some((e, key)) = w.items.get(w.next) {
+            let args = match w.kind {
+                WalkKind::Reduce => alloc::vec![core::mem::replace(&mut w.acc, N::Unit), e.clone()],
+                _ => alloc::vec![e.clone(), key.clone()],
+            };
+            w.next += 1;
+            let f = w.f.clone();
+            return self.call(&f, args, true, span, ctx);
+        }
+        let Some(w) = self.walks.pop() else {
+            return Ok(());
+        };
+        let res = match w.kind {
+            WalkKind::Map => {
+                let arr = N::Array(w.res);
+                ctx.reserve(arr.heap_size(), span)?;
+                arr
+            }
+            WalkKind::MapObject => {
+                let keys = w.items.into_iter().map(|(_, key)| key.to_str());
+                let obj = N::Object(keys.zip(w.res).collect());
+                ctx.reserve(obj.heap_size(), span)?;
+                obj
+            }
+            WalkKind::Filter => N::Array(w.res),
+            WalkKind::Reduce => w.acc,
+        };
+        self.stack.push(res);
+        Ok(())
+    }
+
+    /// Give the result of a call to the last `Walk`
+    fn walk_result(&mut self, res: N) {
+        let Some(w) = self.walks.last_mut() else {
+            return;
+        };
+        match w.kind {
+            WalkKind::Map | WalkKind::MapObject => w.res.push(res),
+            WalkKind::Filter => {
+                if res.to_bool() {
+                    w.res.push(w.items[w.next - 1].0.clone());
+                }
+            }
+            WalkKind::Reduce => w.acc = res,
+        }
+    }
+
+    fn pop(&mut self) -> N {
+        self.stack.pop().unwrap_or(N::Unit)
+    }
+
+    /// Run one instruction, returns the result once the expression is done
+    #[inline(always)]
+    fn step(&mut self, ctx: &mut Ctx) -> Result<Option<N>, RuntimeError> {
+        let Some(frame) = self.frames.last_mut() else {
+            return Ok(Some(N::Unit));
+        };
+        let chunk = &frame.chunk;
+        let (instr, span) = (chunk.code[frame.ip], chunk.spans[frame.ip]);
+        if let Some(fuel) = ctx.fuel.as_mut() {
+            *fuel -= 1;
+        }
+        frame.ip += 1;
+        match instr {
+            Instr::Const(i) => {
+                let n = chunk.consts[i].clone();
+                self.stack.push(n);
+            }
+            Instr::Get(i) => {
+                let name = &chunk.names[i];
+                let n = match ctx.find_var(name) {
+                    Some((_, v)) => load(v),
+                    None => ctx.fail(RuntimeErrorKind::UndefinedVariable(name.clone()), span)?,
+                };
+                self.stack.push(n);
+            }
+            Instr::Set(i) => {
+                let val = self.stack.pop().unwrap_or(N::Unit);
+                ctx.set_val(&chunk.names[i], val);
+                self.stack.push(N::Unit);
+            }
+            Instr::Assign(i) => {
+                let val = self.stack.pop().unwrap_or(N::Unit);
+                let res = assign(&chunk.names[i], val, span, ctx)?;
+                self.stack.push(res);
+            }
+            Instr::GetLocal(slot) => {
+                let n = ctx.local(slot).map_or(N::Unit, |v| load(v));
+                self.stack.push(n);
+            }
+            Instr::SetLocal(slot) => {
+                let val = self.stack.pop().unwrap_or(N::Unit);
+                let res = assign_local(slot, val, ctx);
+                self.stack.push(res);
+            }
+            Instr::GetCell(i) => {
+                let n = load(&chunk.consts[i]);
+                self.stack.push(n);
+            }
+            Instr::SetCell(i) => {
+                let val = self.stack.pop().unwrap_or(N::Unit);
+                let res = match &chunk.consts[i] {
+                    N::Cell(cell) => assign_cell(cell, val, ctx),
+                    _ => N::Unit,
+                };
+                self.stack.push(res);
+            }
+            Instr::Pop => {
+                self.pop();
+            }
+            Instr::Swap => {
+                let len = self.stack.len();
+                self.stack.swap(len - 1, len - 2);
+            }
+            Instr::Binary(op) => {
+                let rt = self.pop();
+                let lt = self.pop();
+                match (op, lt, rt) {
+                    (Op::And, N::Array(v), f @ N::FuncDef { .. }) => {
+                        self.walk(WalkKind::Filter, f, indexed(v), span, ctx)?
+                    }
+                    (Op::Or, N::Array(v), f @ N::FuncDef { .. }) => {
+                        let mut items = v.into_iter().map(|e| (e, N::Unit));
+                        let acc = items.next().map_or(N::Unit, |(e, _)| e);
+                        self.walks.push(Walk {
+                            kind: WalkKind::Reduce,
+                            f,
+                            items: items.collect(),
+                            next: 0,
+                            res: Vec::new(),
+                            acc,
+                            span,
+                        });
+                        self.walk_next(ctx)?;
+                    }
+                    (op, lt, rt) => {
+                        let res = binary(op, lt, rt, span, ctx)?;
+                        self.stack.push(res);
+                    }
+                }
+            }
+            Instr::Unary(op) => {
+                let val = self.pop();
+                let res = unary(op, val, span, ctx)?;
+                self.stack.push(res);
+            }
+            Instr::Jump(to) => frame.ip = to,
+            Instr::ShortCircuit(op, to) => {
+                if let Some(res) = self.stack.last().and_then(|lt| short_circuit(op, lt)) {
+                    self.stack.pop();
+                    self.stack.push(res);
+                    frame.ip = to;
+                }
+            }
+            Instr::JumpIfFalse(to) => {
+                if !self.pop().to_bool() {
+                    self.frames.last_mut().unwrap().ip = to;
+                }
+            }
+            Instr::JumpIfArray(i, to) => {
+                if appends(&chunk.consts[i], ctx) {
+                    frame.ip = to;
+                }
+            }
+            Instr::Append(i) => {
+                let val = self.stack.pop().unwrap_or(N::Unit);
+                let res = append_var(&chunk.consts[i], val, span, ctx)?;
+                self.stack.push(res);
+            }
+            Instr::SetElement(i, len) => {
+                let mut keys = self.stack.split_off(self.stack.len() - len);
+                keys.reverse();
+                let val = self.stack.pop().unwrap_or(N::Unit);
+                let res = store_element(&chunk.consts[i], &keys, val, span, ctx)?;
+                self.stack.push(res);
+            }
+            Instr::Loop(to) => {
+                frame.ip = to;
+                ctx.poll_interrupt(span)?;
+            }
+            Instr::LoopStart(brk, cont) => {
+                self.loops.push(LoopRec {
+                    brk,
+                    cont,
+                    frames: self.frames.len(),
+                    stack: self.stack.len(),
+                    marks: self.marks.len(),
+                    handlers: self.handlers.len(),
+                    cursor: None,
+                });
+            }
+            Instr::For(brk, cont) => match Cursor::new(self.stack.pop().unwrap_or(N::Unit)) {
+                Ok(cursor) => {
+                    self.loops.push(LoopRec {
+                        brk,
+                        cont,
+                        frames: self.frames.len(),
+                        stack: self.stack.len(),
+                        marks: self.marks.len(),
+                        handlers: self.handlers.len(),
+                        cursor: Some(cursor),
+                    });
+                    self.stack.push(N::Unit);
+                }
+                Err(kind) => {
+                    let res = ctx.fail(kind, span)?;
+                    self.stack.push(res);
+                    frame.ip = brk;
+                }
+            },
+            Instr::Next(to) => {
+                let next = self
+                    .loops
+                    .last_mut()
+                    .and_then(|l| l.cursor.as_mut()?.next());
+                match next {
+                    Some((item, key)) => {
+                        self.pop();
+                        self.stack.push(key);
+                        self.stack.push(item);
+                    }
+                    None => frame.ip = to,
+                }
+            }
+            Instr::LoopEnd => {
+                self.loops.pop();
+            }
+            Instr::Enter => self.marks.push(ctx.values.len()),
+            Instr::Leave => {
+                let from = self.marks.pop().unwrap_or(ctx.values.len());
+                ctx.drain(from);
+            }
+            Instr::Call(argc) => {
+                ctx.poll_interrupt(span)?;
+                let args = self.stack.split_off(self.stack.len() - argc);
+                match (self.pop(), args.first()) {
+                    (f @ N::FuncDef { .. }, _) => self.call(&f, args, false, span, ctx)?,
+                    (N::Array(v), Some(N::FuncDef { .. })) => {
+                        let f = args.into_iter().next().unwrap_or(N::Unit);
+                        self.walk(WalkKind::Map, f, indexed(v), span, ctx)?;
+                    }
+                    (N::Object(map), Some(N::FuncDef { .. })) => {
+                        let f = args.into_iter().next().unwrap_or(N::Unit);
+                        let items = map.into_iter().map(|(k, e)| (e, N::Str(k))).collect();
+                        self.walk(WalkKind::MapObject, f, items, span, ctx)?;
+                    }
+                    (f, _) => {
+                        let res = call(&f, args, span, ctx)?;
+                        self.stack.push(res);
+                    }
+                }
+            }
+            Instr::Array(len) => {
+                let arr = N::Array(self.stack.split_off(self.stack.len() - len));
+                ctx.reserve(arr.heap_size(), span)?;
+                self.stack.push(arr);
+            }
+            Instr::Object(i) => {
+                let N::Object(map) = &chunk.consts[i] else {
+                    unreachable!()
+                };
+                let values = self.stack.split_off(self.stack.len() - map.len());
+                let obj = N::Object(map.keys().cloned().zip(values).collect());
+                ctx.reserve(obj.heap_size(), span)?;
+                self.stack.push(obj);
+            }
+            Instr::Concat(len) => {
+                let mut s = String::new();
+                for e in self.stack.split_off(self.stack.len() - len) {
+                    s.push_str(&e.to_str());
+                }
+                ctx.reserve(s.len(), span)?;
+                self.stack.push(N::Str(s));
+            }
+            Instr::Destructure(i) => {
+                let val = self.stack.pop().unwrap_or(N::Unit);
+                let res = destructure(&chunk.patterns[i], val, span, ctx)?;
+                self.stack.push(res);
+            }
+            Instr::Bind(i, to) => {
+                let value = self.stack.last().unwrap_or(&N::Unit);
+                if !bind(&chunk.patterns[i], value, ctx) {
+                    frame.ip = to;
+                }
+            }
+            Instr::Throw => {
+                return Err(RuntimeError {
+                    kind: RuntimeErrorKind::Thrown(self.pop()),
+                    span,
+                })
+            }
+            Instr::Exit => {
+                return Err(RuntimeError {
+                    kind: RuntimeErrorKind::Return(self.pop()),
+                    span,
+                })
+            }
+            Instr::Break => {
+                return Err(RuntimeError {
+                    kind: RuntimeErrorKind::Break(self.pop()),
+                    span,
+                })
+            }
+            Instr::Continue => {
+                return Err(RuntimeError {
+                    kind: RuntimeErrorKind::Continue,
+                    span,
+                })
+            }
+            Instr::Try(ip) => {
+                self.handlers.push(Handler {
+                    ip,
+                    frames: self.frames.len(),
+                    stack: self.stack.len(),
+                    marks: self.marks.len(),
+                    values: ctx.values.len(),
+                    deep: ctx.deep,
+                    strict: core::mem::replace(&mut ctx.strict, true),
+                    frame: ctx.frame,
+                    loops: self.loops.len(),
+                    walks: self.walks.len(),
+                });
+            }
+            Instr::EndTry => {
+                if let Some(handler) = self.handlers.pop() {
+                    ctx.strict = handler.strict;
+                }
+            }
+            Instr::Eval(i) => {
+                let n = chunk.consts[i].clone();
+                // Doesn't run script code, not counted by the fuel of the tree-walker
+                let res = eval_match(&n, ctx)?;
+                self.stack.push(res);
+            }
+            Instr::Return => {
+                let res = self.pop();
+                match self.frames.pop() {
+                    Some(Frame {
+                        base: Some(base),
+                        caller,
+                        walk,
+                        ..
+                    }) => {
+                        ctx.drain(base);
+                        ctx.deep -= 1;
+                        ctx.frame = caller;
+                        if walk {
+                            self.walk_result(res);
+                            self.walk_next(ctx)?;
+                        } else {
+                            self.stack.push(res);
+                        }
+                    }
+                    _ => return Ok(Some(res)),
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Elements of an array with their index
+fn indexed(v: Vec<N>) -> Vec<(N, N)> {
+    v.into_iter()
+        .enumerate()
+        .map(|(index, e)| (e, N::Num(index as f64)))
+        .collect()
+}
+
+/// Compile and run the node with a [Vm], like [eval](crate::eval)
+pub fn eval(n: &N, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    Vm::new(n).eval(ctx)
+}
+
+/// Compile and run the node with a [Vm], like [try_eval](crate::try_eval)
+///
+/// The `Vm` is dropped, when it runs out of fuel its variables are forgotten.
+pub fn try_eval(n: &N, ctx: &mut Ctx) -> Result<N, RuntimeError> {
+    let mut vm = Vm::new(n);
+    let res = vm.try_eval(ctx);
+    vm.stop(ctx);
+    res
+}
+
+/// Like [parse_eval](crate::parse_eval), with the bytecode backend
+pub fn parse_eval(code: &str) -> Result<N, RuntimeError> {
+    let mut ctx = Ctx::new();
+    ctx.insert_code(code);
+    let mut res = N::Unit;
+    while let Ok(parent) = ctx.parse_next_expr() {
+        res = eval(&parent, &mut ctx)?;
+    }
+    Ok(res)
+}
+
+/// Like [try_parse_eval](crate::try_parse_eval), with the bytecode backend
+pub fn try_parse_eval(code: &str) -> Result<N, RuntimeError> {
+    let mut ctx = Ctx::new();
+    ctx.insert_code(code);
+    let mut res = N::Unit;
+    while let Some(parent) = ctx.next_expr()? {
+        res = try_eval(&parent, &mut ctx)?;
+    }
+    Ok(res)
+}